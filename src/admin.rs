@@ -0,0 +1,522 @@
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fs;
+use std::net::IpAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, HeaderField, Method, Request, Response, Server};
+
+use crate::metrics::{self, Endpoint, METRICS};
+use crate::settings::{Settings, SharedSettings};
+use crate::{IpWhitelist, WhitelistElement};
+
+#[derive(Serialize)]
+struct Authorization {
+    ip: IpAddr,
+    authorized_at: DateTime<Utc>,
+    valid_until: DateTime<Utc>,
+    headers: BTreeMap<String, String>,
+}
+
+impl Authorization {
+    fn new(ip: IpAddr, element: &WhitelistElement) -> Self {
+        Self {
+            ip,
+            authorized_at: element.authorized_at,
+            valid_until: element.valid_until,
+            headers: element
+                .headers
+                .iter()
+                .map(|x| (x.field.to_string(), x.value.to_string()))
+                .collect(),
+        }
+    }
+}
+
+/// Body of a `PATCH` request changing the expiry of an authorization. `extend` is in seconds and
+/// may be negative.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Change {
+    valid_until: Option<DateTime<Utc>>,
+    extend: Option<i64>,
+}
+
+/// Body of a `POST` request creating an authorization. `ttl` is in seconds and replaces the
+/// configured expiry.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewAuthorization {
+    ip: IpAddr,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    ttl: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Status {
+    version: &'static str,
+    pid: u32,
+    authorizations: usize,
+}
+
+#[derive(Serialize)]
+struct Revoked {
+    revoked: Vec<IpAddr>,
+}
+
+#[derive(Serialize)]
+struct Reloaded {
+    reloaded: bool,
+}
+
+#[derive(Serialize)]
+struct Error<'a> {
+    error: &'a str,
+}
+
+pub type JsonResponse = Response<std::io::Cursor<Vec<u8>>>;
+
+pub fn json(status: u16, value: &impl Serialize) -> JsonResponse {
+    Response::from_data(serde_json::to_vec(value).unwrap())
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+fn error(status: u16, message: &str) -> JsonResponse {
+    json(status, &Error { error: message })
+}
+
+/// Compares two byte strings in time only depending on their length.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Starts the admin server on `address`, which is either a TCP address or `unix:` followed by
+/// the path of a Unix domain socket that is created with the permissions `mode`.
+pub fn bind(address: &str, mode: u32) -> Result<Server, Box<dyn StdError + Send + Sync>> {
+    let Some(path) = address.strip_prefix("unix:") else {
+        return Server::http(address);
+    };
+    let path = Path::new(path);
+    let name = path.file_name().ok_or("Missing socket file name")?;
+    match fs::symlink_metadata(path) {
+        Ok(x) if x.file_type().is_socket() => {
+            debug!("Removing stale socket {}", path.display());
+            fs::remove_file(path)?;
+        }
+        Ok(_) => return Err(format!("{} exists and is not a socket", path.display()).into()),
+        Err(_) => (),
+    }
+    // The socket is created in a private directory and only linked into place once it has its
+    // permissions, so it is never accessible with the ones from the umask. Unlike a rename, the
+    // link fails instead of replacing a file created in the meantime.
+    let mut private = name.to_os_string();
+    private.push(format!(".{}.tmp", std::process::id()));
+    let private = path.with_file_name(private);
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let socket = private.join("socket");
+    let server = Server::http_unix(&socket).and_then(|server| {
+        fs::set_permissions(&socket, fs::Permissions::from_mode(mode))?;
+        fs::hard_link(&socket, path)?;
+        Ok(server)
+    });
+    let _ = fs::remove_file(&socket);
+    let _ = fs::remove_dir(&private);
+    server
+}
+
+/// Serves the admin API on the separate admin listener.
+pub fn server_thread(server: Arc<Server>, shared: &SharedSettings, whitelist: Arc<IpWhitelist>) {
+    while let Ok(rq) = server.recv() {
+        trace!(
+            "received admin request. method: {:?}, url: {:?}",
+            rq.method(),
+            rq.url()
+        );
+        let start = Instant::now();
+        if rq.url() == "/metrics" {
+            metrics::handle(&whitelist, rq);
+        } else if rq.url().starts_with("/admin/") {
+            handle(shared, &whitelist, rq);
+        } else {
+            let _ = rq.respond(Response::from_string("not found").with_status_code(404));
+        }
+        METRICS.request(Endpoint::Admin, start.elapsed());
+    }
+    debug!("Admin thread exit");
+}
+
+/// Without an `admin_token` only requests to an admin listener on a Unix domain socket are
+/// accepted, access to it is restricted by its permissions.
+fn authenticated(settings: &Settings, rq: &Request) -> bool {
+    let Some(token) = &settings.admin_token else {
+        return settings
+            .admin_listen
+            .as_deref()
+            .is_some_and(|x| x.starts_with("unix:"));
+    };
+    let field = HeaderField::from_str("Authorization").unwrap();
+    rq.headers()
+        .iter()
+        .filter(|x| x.field == field)
+        .filter_map(|x| x.value.as_str().strip_prefix("Bearer "))
+        .any(|x| constant_time_eq(x.trim().as_bytes(), token.as_bytes()))
+}
+
+/// Handles a request below `/admin/`.
+pub fn handle(shared: &SharedSettings, whitelist: &IpWhitelist, mut rq: Request) {
+    let settings = &shared.load();
+    if settings.admin_token.is_none() && settings.admin_listen.is_none() {
+        let _ = rq.respond(Response::from_string("not found").with_status_code(404));
+        return;
+    }
+    if !authenticated(settings, &rq) {
+        warn!("Unauthenticated admin request for {}", rq.url());
+        let _ = rq.respond(
+            error(401, "Unauthorized")
+                .with_header(Header::from_bytes("WWW-Authenticate", "Bearer").unwrap()),
+        );
+        return;
+    }
+
+    let path = rq.url().split('?').next().unwrap_or_default();
+    let segments: Vec<String> = path
+        .trim_start_matches("/admin/")
+        .split('/')
+        .map(percent_decode)
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let mut body = String::new();
+    if rq.as_reader().read_to_string(&mut body).is_err() {
+        let _ = rq.respond(error(400, "Invalid body"));
+        return;
+    }
+
+    let response = match (rq.method(), segments.as_slice()) {
+        (Method::Get, ["status"]) => status(whitelist),
+        (Method::Get, ["authorizations"]) => list(whitelist),
+        (Method::Post, ["authorizations"]) => create(settings, whitelist, &body),
+        (Method::Get, ["authorizations", ip]) => with_ip(ip, |ip| get(whitelist, ip)),
+        (Method::Patch, ["authorizations", ip]) => with_ip(ip, |ip| change(whitelist, ip, &body)),
+        (Method::Delete, ["authorizations", ip]) => with_ip(ip, |ip| revoke(whitelist, ip)),
+        (Method::Delete, ["users", user]) => revoke_user(settings, whitelist, user),
+        (Method::Post, ["reload"]) => reload(shared, whitelist),
+        (
+            _,
+            ["status"] | ["authorizations"] | ["authorizations", _] | ["users", _] | ["reload"],
+        ) => error(405, "Method not allowed"),
+        _ => error(404, "Not found"),
+    };
+    let _ = rq.respond(response);
+}
+
+fn with_ip(ip: &str, f: impl FnOnce(&IpAddr) -> JsonResponse) -> JsonResponse {
+    match IpAddr::from_str(ip) {
+        Ok(ip) => f(&ip),
+        Err(_) => error(400, "Invalid ip address"),
+    }
+}
+
+fn list(whitelist: &IpWhitelist) -> JsonResponse {
+    let now = Utc::now();
+    let mut list: Vec<_> = whitelist
+        .entries()
+        .into_iter()
+        .filter(|(_, x)| x.valid_until > now)
+        .map(|(ip, x)| Authorization::new(ip, &x))
+        .collect();
+    list.sort_by_key(|x| x.ip);
+    json(200, &list)
+}
+
+fn status(whitelist: &IpWhitelist) -> JsonResponse {
+    let now = Utc::now();
+    let status = Status {
+        version: env!("CARGO_PKG_VERSION"),
+        pid: std::process::id(),
+        authorizations: whitelist
+            .entries()
+            .iter()
+            .filter(|(_, x)| x.valid_until > now)
+            .count(),
+    };
+    json(200, &status)
+}
+
+fn create(settings: &Settings, whitelist: &IpWhitelist, body: &str) -> JsonResponse {
+    let new = match serde_json::from_str::<NewAuthorization>(body) {
+        Ok(x) => x,
+        Err(e) => return error(400, &e.to_string()),
+    };
+    // Check the address as it is stored, so a mapped or shortened form can't bypass the list
+    if settings.is_deny_listed(&new.ip.to_canonical())
+        || settings.is_deny_listed(&whitelist.key(&new.ip))
+    {
+        return error(403, "The ip address is in the deny list");
+    }
+    let mut headers = Vec::new();
+    for (field, value) in &new.headers {
+        match Header::from_bytes(field.as_bytes(), value.as_bytes()) {
+            Ok(x) => headers.push(x),
+            Err(_) => return error(400, &format!("Invalid header \"{field}\"")),
+        }
+    }
+    let valid_until = new.ttl.map(|x| Utc::now() + TimeDelta::seconds(x.into()));
+    let (ip, x) = whitelist.allow_until(&new.ip, &headers, valid_until);
+    info!(
+        "Authorized {ip} until {} through the admin API with headers: {}",
+        x.valid_until,
+        headers
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .join("; ")
+    );
+    json(201, &Authorization::new(ip, &x))
+}
+
+fn get(whitelist: &IpWhitelist, ip: &IpAddr) -> JsonResponse {
+    match whitelist.get_ip(ip) {
+        Some(x) if x.valid_until > Utc::now() => {
+            json(200, &Authorization::new(whitelist.key(ip), &x))
+        }
+        _ => error(404, "Not authorized"),
+    }
+}
+
+fn change(whitelist: &IpWhitelist, ip: &IpAddr, body: &str) -> JsonResponse {
+    let change = match serde_json::from_str::<Change>(body) {
+        Ok(x) => x,
+        Err(e) => return error(400, &e.to_string()),
+    };
+    if change.valid_until.is_some() == change.extend.is_some() {
+        return error(400, "Expected either valid_until or extend");
+    }
+    let extend = match change.extend.map(TimeDelta::try_seconds) {
+        Some(None) => return error(400, "extend is out of range"),
+        x => x.flatten(),
+    };
+    // Nothing in here may panic, the whitelist is locked
+    let mut out_of_range = false;
+    let update = |x: &mut WhitelistElement| match (change.valid_until, extend) {
+        (Some(valid_until), None) => x.valid_until = valid_until,
+        (None, Some(extend)) => match x.valid_until.checked_add_signed(extend) {
+            Some(valid_until) => x.valid_until = valid_until,
+            None => out_of_range = true,
+        },
+        _ => (),
+    };
+    let updated = whitelist.update(ip, update);
+    if out_of_range {
+        return error(400, "extend is out of range");
+    }
+    match updated {
+        Some((ip, x)) => {
+            info!("Changed expiry of {ip} to {}", x.valid_until);
+            json(200, &Authorization::new(ip, &x))
+        }
+        None => error(404, "Not authorized"),
+    }
+}
+
+fn revoke(whitelist: &IpWhitelist, ip: &IpAddr) -> JsonResponse {
+    let revoked: Vec<_> = whitelist.revoke(ip).into_iter().collect();
+    if revoked.is_empty() {
+        return error(404, "Not authorized");
+    }
+    info!("Revoked {}", revoked[0]);
+    json(200, &Revoked { revoked })
+}
+
+fn revoke_user(settings: &Settings, whitelist: &IpWhitelist, user: &str) -> JsonResponse {
+    let revoked = whitelist.revoke_where(|x| {
+        x.headers
+            .iter()
+            .any(|x| x.field == settings.user_header && x.value.as_str() == user)
+    });
+    info!("Revoked {} authorizations of user {user}", revoked.len());
+    json(200, &Revoked { revoked })
+}
+
+fn reload(shared: &SharedSettings, whitelist: &IpWhitelist) -> JsonResponse {
+    info!("Reloading config through the admin API");
+    match crate::reload(shared, whitelist) {
+        Ok(()) => json(200, &Reloaded { reloaded: true }),
+        Err(e) => {
+            error!("{e}, keeping the current config");
+            error(422, &e)
+        }
+    }
+}
+
+/// Decodes `%XX` escapes in a path segment.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let decoded = (bytes[i] == b'%')
+            .then(|| s.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match decoded {
+            Some(x) => {
+                result.push(x);
+                i += 3;
+            }
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expiry::ExpiryPolicy;
+
+    fn setup(toml: &str) -> (Settings, IpWhitelist) {
+        let settings = Settings::for_test(toml).unwrap();
+        let whitelist = IpWhitelist::build(
+            ExpiryPolicy::new(&settings),
+            settings.ipv6_prefix_length,
+            None,
+        );
+        (settings, whitelist)
+    }
+
+    #[test]
+    fn admin_listen_requires_token() {
+        let error = Settings::for_test("admin_listen = \"0.0.0.0:9090\"")
+            .err()
+            .unwrap();
+        assert!(
+            error.to_string().contains("requires admin_token"),
+            "{error}"
+        );
+        setup("admin_listen = \"0.0.0.0:9090\"\nadmin_token = \"secret\"");
+        setup("admin_listen = \"unix:/run/ip-manager.sock\"");
+    }
+
+    #[test]
+    fn bind_socket() {
+        let dir = std::env::temp_dir().join(format!("ip-manager-{}-bind", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("admin.sock");
+        // A stale socket is replaced
+        for _ in 0..2 {
+            let server = bind(&format!("unix:{}", path.display()), 0o600).unwrap();
+            drop(server);
+            let metadata = fs::symlink_metadata(&path).unwrap();
+            assert!(metadata.file_type().is_socket());
+            assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bind_over_file() {
+        let dir = std::env::temp_dir().join(format!("ip-manager-{}-file", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "threads = 4").unwrap();
+        let error = bind(&format!("unix:{}", path.display()), 0o600)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("is not a socket"), "{error}");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "threads = 4");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn change_expiry() {
+        let (_, whitelist) = setup("");
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        whitelist.allow(&ip, &[]);
+        let before = whitelist.get_ip(&ip).unwrap().valid_until;
+
+        let response = change(&whitelist, &ip, r#"{"extend": 60}"#);
+        assert_eq!(response.status_code().0, 200);
+        let after = whitelist.get_ip(&ip).unwrap().valid_until;
+        assert_eq!(after - before, TimeDelta::seconds(60));
+
+        let response = change(
+            &whitelist,
+            &ip,
+            r#"{"valid_until": "2030-01-01T00:00:00Z"}"#,
+        );
+        assert_eq!(response.status_code().0, 200);
+        let response = change(
+            &whitelist,
+            &"192.0.2.2".parse().unwrap(),
+            r#"{"extend": 60}"#,
+        );
+        assert_eq!(response.status_code().0, 404);
+        for body in [
+            r#"{}"#,
+            r#"{"extend": 60, "valid_until": "2030-01-01T00:00:00Z"}"#,
+            r#"{"extend": "soon"}"#,
+        ] {
+            assert_eq!(change(&whitelist, &ip, body).status_code().0, 400, "{body}");
+        }
+    }
+
+    #[test]
+    fn change_out_of_range() {
+        let (_, whitelist) = setup("");
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        whitelist.allow(&ip, &[]);
+        let before = whitelist.get_ip(&ip).unwrap().valid_until;
+        // Too large for a TimeDelta, and too large for a DateTime
+        for extend in [
+            i64::MAX,
+            i64::MIN,
+            1_000_000_000_000_000,
+            -1_000_000_000_000_000,
+        ] {
+            let response = change(&whitelist, &ip, &format!(r#"{{"extend": {extend}}}"#));
+            assert_eq!(response.status_code().0, 400, "{extend}");
+        }
+        // The whitelist is still usable and unchanged
+        assert_eq!(whitelist.get_ip(&ip).unwrap().valid_until, before);
+        assert!(whitelist.is_allowed(&ip).is_ok());
+    }
+
+    #[test]
+    fn create_denied() {
+        let (settings, whitelist) = setup(
+            r#"
+            deny_list = ["192.0.2.1", "2001:db8::/64"]
+            ipv6_prefix_length = 48
+            "#,
+        );
+        for ip in [
+            "192.0.2.1",
+            "::ffff:192.0.2.1",
+            "2001:db8::1",
+            "2001:db8:0:1::1",
+        ] {
+            let response = create(&settings, &whitelist, &format!(r#"{{"ip": "{ip}"}}"#));
+            assert_eq!(response.status_code().0, 403, "{ip}");
+        }
+        assert_eq!(whitelist.len(), 0);
+        let response = create(&settings, &whitelist, r#"{"ip": "::ffff:192.0.2.2"}"#);
+        assert_eq!(response.status_code().0, 201);
+        assert!(whitelist.get_ip(&"192.0.2.2".parse().unwrap()).is_some());
+    }
+}
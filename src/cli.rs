@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::settings::Settings;
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file. Overrides the CONFIG environment variable
+    #[arg(long, short, global = true)]
+    pub config: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the server (default)
+    Serve,
+    #[command(flatten)]
+    Client(ClientCommand),
+}
+
+// Commands talking to the running instance, flattened into `Command`
+#[derive(Subcommand)]
+pub enum ClientCommand {
+    /// Show the status of the running instance
+    Status,
+    /// List all authorizations
+    List,
+    /// Authorize an ip address
+    Authorize {
+        ip: IpAddr,
+        /// Value for the user header (see `user_header`)
+        #[arg(long)]
+        user: Option<String>,
+        /// Additional header to save, as `Name=value`
+        #[arg(long = "header", value_parser = parse_header)]
+        headers: Vec<(String, String)>,
+        /// Lifetime of the authorization like `90m` or `2h`, instead of the configured expiry
+        #[arg(long, value_parser = parse_duration)]
+        ttl: Option<u32>,
+    },
+    /// Revoke the authorization of an ip address
+    Revoke { ip: IpAddr },
+    /// Reload the config file of the running instance
+    Reload,
+}
+
+#[derive(Serialize)]
+struct NewAuthorization {
+    ip: IpAddr,
+    headers: BTreeMap<String, String>,
+    ttl: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    ip: IpAddr,
+    valid_until: String,
+    headers: BTreeMap<String, String>,
+}
+
+/// Parses a duration like `30s`, `90m`, `2h` or `1d12h` into seconds. A plain number is in seconds.
+fn parse_duration(s: &str) -> Result<u32, String> {
+    if let Ok(x) = s.parse() {
+        return Ok(x);
+    }
+    if s.is_empty() {
+        return Err("missing number".into());
+    }
+    let mut total: u32 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(format!("invalid unit '{c}'")),
+        };
+        let value: u32 = number.parse().map_err(|_| "missing number".to_string())?;
+        total = value
+            .checked_mul(unit)
+            .and_then(|x| total.checked_add(x))
+            .ok_or("duration too long")?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err("missing unit after number".into());
+    }
+    Ok(total)
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("expected Name=value, got \"{s}\""))
+}
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+/// Sends a request to the admin API of the running instance and returns the status code and the
+/// body of the response.
+fn request(
+    settings: &Settings,
+    method: &str,
+    path: &str,
+    body: Option<String>,
+) -> io::Result<(u16, String)> {
+    let address = settings
+        .admin_listen
+        .as_deref()
+        .unwrap_or(&settings.listen_address);
+    let mut stream: Box<dyn Stream> = match address.strip_prefix("unix:") {
+        Some(path) => Box::new(UnixStream::connect(path)?),
+        None => Box::new(TcpStream::connect(address)?),
+    };
+    // Without admin_listen the API is behind the PROXY protocol listener
+    if settings.admin_listen.is_none() && settings.proxy_protocol {
+        stream.write_all(b"PROXY UNKNOWN\r\n")?;
+    }
+
+    let body = body.unwrap_or_default();
+    let mut rq = format!(
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+        body.len()
+    );
+    if let Some(token) = &settings.admin_token {
+        rq.push_str(&format!("Authorization: Bearer {token}\r\n"));
+    }
+    if !body.is_empty() {
+        rq.push_str("Content-Type: application/json\r\n");
+    }
+    rq.push_str("\r\n");
+    rq.push_str(&body);
+    stream.write_all(rq.as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid response");
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(invalid)?;
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((status, body.to_string()))
+}
+
+/// Runs a client command against the running instance.
+pub fn run(settings: &Settings, command: ClientCommand) -> ExitCode {
+    let (method, path, body) = match &command {
+        ClientCommand::Status => ("GET", "/admin/status".to_string(), None),
+        ClientCommand::List => ("GET", "/admin/authorizations".to_string(), None),
+        ClientCommand::Authorize {
+            ip,
+            user,
+            headers,
+            ttl,
+        } => {
+            let mut headers: BTreeMap<_, _> = headers.iter().cloned().collect();
+            if let Some(user) = user {
+                headers.insert(settings.user_header.to_string(), user.clone());
+            }
+            let body = NewAuthorization {
+                ip: *ip,
+                headers,
+                ttl: *ttl,
+            };
+            let body = serde_json::to_string(&body).unwrap();
+            ("POST", "/admin/authorizations".to_string(), Some(body))
+        }
+        ClientCommand::Revoke { ip } => ("DELETE", format!("/admin/authorizations/{ip}"), None),
+        ClientCommand::Reload => ("POST", "/admin/reload".to_string(), None),
+    };
+
+    let (status, body) = match request(settings, method, &path, body) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to connect to ip-manager: {e}");
+            if settings.admin_listen.is_none() && settings.proxy_protocol {
+                eprintln!(
+                    "With proxy_protocol, listen_address only accepts connections from \
+                     trusted_proxies. Add this host to trusted_proxies or set admin_listen"
+                );
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+    if !(200..300).contains(&status) {
+        let message = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|x| x["error"].as_str().map(String::from))
+            .unwrap_or(body);
+        eprintln!("Error {status}: {message}");
+        return ExitCode::FAILURE;
+    }
+
+    match command {
+        ClientCommand::List => match serde_json::from_str::<Vec<Authorization>>(&body) {
+            Ok(list) => {
+                for x in list {
+                    let headers: Vec<_> =
+                        x.headers.iter().map(|(k, v)| format!("{k}: {v}")).collect();
+                    println!("{:<40} {:<32} {}", x.ip, x.valid_until, headers.join("; "));
+                }
+            }
+            Err(_) => println!("{body}"),
+        },
+        ClientCommand::Authorize { .. } => match serde_json::from_str::<Authorization>(&body) {
+            Ok(x) => println!("Authorized {} until {}", x.ip, x.valid_until),
+            Err(_) => println!("{body}"),
+        },
+        ClientCommand::Revoke { ip } => println!("Revoked {ip}"),
+        ClientCommand::Reload => println!("Reloaded config"),
+        _ => match serde_json::from_str::<Value>(&body) {
+            Ok(Value::Object(x)) => {
+                for (k, v) in x {
+                    println!("{k}: {}", v.as_str().map_or(v.to_string(), String::from));
+                }
+            }
+            _ => println!("{body}"),
+        },
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("30s"), Ok(30));
+        assert_eq!(parse_duration("90m"), Ok(5400));
+        assert_eq!(parse_duration("2h"), Ok(7200));
+        assert_eq!(parse_duration("1d12h"), Ok(129600));
+        assert_eq!(parse_duration("1h30m15s"), Ok(5415));
+        assert_eq!(parse_duration("0m"), Ok(0));
+    }
+
+    #[test]
+    fn invalid_durations() {
+        for x in [
+            "",
+            "h",
+            "2x",
+            "2h30",
+            "1.5h",
+            "-1h",
+            "2 h",
+            "50000d",
+            "4294967296",
+        ] {
+            assert!(parse_duration(x).is_err(), "{x}");
+        }
+    }
+}
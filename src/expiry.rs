@@ -0,0 +1,499 @@
+use chrono::prelude::*;
+use chrono::Days;
+use chrono::LocalResult;
+use chrono::TimeDelta;
+use chrono_tz::Tz;
+use log::{error, trace};
+use serde::Deserialize;
+
+use crate::schedule::Schedule;
+use crate::settings::Settings;
+
+/// Minimum amount by which a sliding expiry is pushed back, so an active client does not cause a
+/// journal write on every request.
+const REFRESH_GRANULARITY: TimeDelta = TimeDelta::minutes(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiryMode {
+    /// Revoke at a fixed local time of day
+    Fixed,
+    /// Revoke a fixed amount of time after the authorization
+    Ttl,
+    /// Revoke after a period without requests
+    Sliding,
+    /// Revoke at the next time matching one of the cron-like schedules
+    Schedule,
+}
+
+/// Decides when an authorization expires.
+#[derive(PartialEq)]
+pub enum ExpiryPolicy {
+    Fixed {
+        minute: u8,
+        hour: u8,
+        days: u32,
+        timezone: Tz,
+    },
+    Ttl(TimeDelta),
+    Schedule {
+        schedules: Vec<Schedule>,
+        timezone: Tz,
+    },
+    Sliding {
+        idle_timeout: TimeDelta,
+        max_lifetime: Option<TimeDelta>,
+    },
+}
+
+impl ExpiryPolicy {
+    pub fn new(settings: &Settings) -> Self {
+        match settings.expiry {
+            ExpiryMode::Fixed => Self::Fixed {
+                minute: settings.minute,
+                hour: settings.hour,
+                days: settings.days,
+                timezone: settings.timezone,
+            },
+            ExpiryMode::Ttl => Self::Ttl(TimeDelta::seconds(settings.ttl.into())),
+            ExpiryMode::Schedule => Self::Schedule {
+                schedules: settings.schedule.clone(),
+                timezone: settings.timezone,
+            },
+            ExpiryMode::Sliding => Self::Sliding {
+                idle_timeout: TimeDelta::seconds(settings.idle_timeout.into()),
+                max_lifetime: settings.max_lifetime.map(|x| TimeDelta::seconds(x.into())),
+            },
+        }
+    }
+
+    /// The expiry of an authorization made at `now`.
+    pub fn valid_until(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Self::Fixed {
+                minute,
+                hour,
+                days,
+                timezone,
+            } => fixed_time(now, *minute, *hour, *days, timezone),
+            Self::Ttl(ttl) => now + *ttl,
+            Self::Schedule {
+                schedules,
+                timezone,
+            } => scheduled_time(now, schedules, timezone).unwrap_or_else(|| {
+                error!("No schedule matches after {now}, the authorization expires immediately");
+                now
+            }),
+            Self::Sliding {
+                idle_timeout,
+                max_lifetime,
+            } => capped(now + *idle_timeout, now, max_lifetime),
+        }
+    }
+
+    /// The new expiry of an authorization made at `authorized_at` and currently valid until
+    /// `valid_until` after it was used at `now`, if it changes.
+    pub fn refresh(
+        &self,
+        authorized_at: DateTime<Utc>,
+        valid_until: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let Self::Sliding {
+            idle_timeout,
+            max_lifetime,
+        } = self
+        else {
+            return None;
+        };
+        let new = capped(now + *idle_timeout, authorized_at, max_lifetime);
+        (new - valid_until >= REFRESH_GRANULARITY).then_some(new)
+    }
+
+    /// The expiry of an authorization made at `authorized_at` and currently valid until
+    /// `valid_until` when this policy replaces another one at `now`. Authorizations are only ever
+    /// shortened by a new policy.
+    pub fn reapply(
+        &self,
+        authorized_at: DateTime<Utc>,
+        valid_until: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> DateTime<Utc> {
+        let new = match self {
+            Self::Sliding {
+                idle_timeout,
+                max_lifetime,
+            } => capped(now + *idle_timeout, authorized_at, max_lifetime),
+            _ => self.valid_until(authorized_at),
+        };
+        valid_until.min(new)
+    }
+}
+
+fn capped(
+    valid_until: DateTime<Utc>,
+    authorized_at: DateTime<Utc>,
+    max_lifetime: &Option<TimeDelta>,
+) -> DateTime<Utc> {
+    match max_lifetime {
+        Some(x) => valid_until.min(authorized_at + *x),
+        None => valid_until,
+    }
+}
+
+/// The first time `hour`:`minute` in `timezone` after `days` additional days.
+///
+/// If the time is skipped by a daylight saving time transition, the end of the gap is used. If it
+/// occurs twice, the first occurrence still in the future is used.
+fn fixed_time(now: DateTime<Utc>, minute: u8, hour: u8, days: u32, timezone: &Tz) -> DateTime<Utc> {
+    let time = NaiveTime::from_hms_opt(hour.into(), minute.into(), 0).unwrap();
+    let local = now.with_timezone(timezone);
+    trace!("{:#}", local);
+    let mut date = local
+        .date_naive()
+        .checked_add_days(Days::new(days.into()))
+        .unwrap();
+    if local.time() > time {
+        date = date.succ_opt().unwrap();
+    }
+    next_local_time(now, date, timezone, |_| vec![time]).unwrap()
+}
+
+/// The first time after `now` matching any of `schedules` in `timezone`, if there is one within
+/// the next 28 years.
+fn scheduled_time(
+    now: DateTime<Utc>,
+    schedules: &[Schedule],
+    timezone: &Tz,
+) -> Option<DateTime<Utc>> {
+    let date = now.with_timezone(timezone).date_naive();
+    next_local_time(now, date, timezone, |date| {
+        let mut times: Vec<_> = schedules.iter().flat_map(|x| x.times_on(date)).collect();
+        times.sort();
+        times.dedup();
+        times
+    })
+}
+
+/// Searches the days starting at `date` for the first of their local `times` after `now`.
+///
+/// Times skipped by a daylight saving time transition are moved to the end of the gap. Times that
+/// occur twice are tried in order.
+fn next_local_time(
+    now: DateTime<Utc>,
+    mut date: NaiveDate,
+    timezone: &Tz,
+    times: impl Fn(NaiveDate) -> Vec<NaiveTime>,
+) -> Option<DateTime<Utc>> {
+    // Long enough for the 29th of February on a given weekday
+    for _ in 0..366 * 28 {
+        for time in times(date) {
+            let candidates = match timezone.from_local_datetime(&date.and_time(time)) {
+                LocalResult::Single(x) => vec![x],
+                LocalResult::Ambiguous(earliest, latest) => vec![earliest, latest],
+                LocalResult::None => vec![end_of_gap(date.and_time(time), timezone)],
+            };
+            if let Some(x) = candidates.into_iter().find(|x| *x > now) {
+                trace!("{:#}", x);
+                return Some(x.with_timezone(&Utc));
+            }
+        }
+        date = date.succ_opt()?;
+    }
+    None
+}
+
+/// The first existing local time after `time`, which falls into a gap of `timezone`.
+fn end_of_gap(time: NaiveDateTime, timezone: &Tz) -> DateTime<Tz> {
+    let mut time = time;
+    loop {
+        time += TimeDelta::minutes(1);
+        if let Some(x) = timezone.from_local_datetime(&time).earliest() {
+            return x;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use validator::Validate;
+
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn ttl() {
+        let policy = ExpiryPolicy::Ttl(TimeDelta::hours(1));
+        let start = utc("2024-06-01T12:00:00Z");
+        let valid_until = policy.valid_until(start);
+        assert_eq!(valid_until, utc("2024-06-01T13:00:00Z"));
+        // Requests don't extend it
+        for now in ["2024-06-01T12:00:00Z", "2024-06-01T12:59:00Z"] {
+            assert_eq!(policy.refresh(start, valid_until, utc(now)), None);
+        }
+    }
+
+    #[test]
+    fn sliding() {
+        let policy = ExpiryPolicy::Sliding {
+            idle_timeout: TimeDelta::hours(1),
+            max_lifetime: Some(TimeDelta::hours(3)),
+        };
+        let start = utc("2024-06-01T12:00:00Z");
+        assert_eq!(policy.valid_until(start), utc("2024-06-01T13:00:00Z"));
+        assert_eq!(
+            policy.refresh(
+                start,
+                utc("2024-06-01T13:00:00Z"),
+                utc("2024-06-01T12:30:00Z")
+            ),
+            Some(utc("2024-06-01T13:30:00Z"))
+        );
+        // Less than a minute later it is not moved
+        assert_eq!(
+            policy.refresh(
+                start,
+                utc("2024-06-01T13:30:00Z"),
+                utc("2024-06-01T12:30:59Z")
+            ),
+            None
+        );
+        // Up to the maximum lifetime
+        assert_eq!(
+            policy.refresh(
+                start,
+                utc("2024-06-01T14:30:00Z"),
+                utc("2024-06-01T14:45:00Z")
+            ),
+            Some(utc("2024-06-01T15:00:00Z"))
+        );
+        assert_eq!(
+            policy.refresh(
+                start,
+                utc("2024-06-01T15:00:00Z"),
+                utc("2024-06-01T14:59:00Z")
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn sliding_without_maximum() {
+        let policy = ExpiryPolicy::Sliding {
+            idle_timeout: TimeDelta::hours(1),
+            max_lifetime: None,
+        };
+        let start = utc("2024-06-01T12:00:00Z");
+        assert_eq!(
+            policy.refresh(
+                start,
+                utc("2024-06-03T12:00:00Z"),
+                utc("2024-06-03T11:30:00Z")
+            ),
+            Some(utc("2024-06-03T12:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn reapply_never_extends() {
+        let start = utc("2024-06-01T12:00:00Z");
+        let now = utc("2024-06-01T12:30:00Z");
+        let sliding = ExpiryPolicy::Sliding {
+            idle_timeout: TimeDelta::minutes(10),
+            max_lifetime: None,
+        };
+        assert_eq!(
+            sliding.reapply(start, utc("2024-06-01T14:00:00Z"), now),
+            utc("2024-06-01T12:40:00Z")
+        );
+        let ttl = ExpiryPolicy::Ttl(TimeDelta::hours(5));
+        assert_eq!(
+            ttl.reapply(start, utc("2024-06-01T14:00:00Z"), now),
+            utc("2024-06-01T14:00:00Z")
+        );
+        let ttl = ExpiryPolicy::Ttl(TimeDelta::hours(1));
+        assert_eq!(
+            ttl.reapply(start, utc("2024-06-01T14:00:00Z"), now),
+            utc("2024-06-01T13:00:00Z")
+        );
+    }
+
+    #[test]
+    fn fixed_time_utc() {
+        let tz = Tz::UTC;
+        assert_eq!(
+            fixed_time(utc("2024-06-01T01:00:00Z"), 0, 3, 0, &tz),
+            utc("2024-06-01T03:00:00Z")
+        );
+        assert_eq!(
+            fixed_time(utc("2024-06-01T04:00:00Z"), 0, 3, 0, &tz),
+            utc("2024-06-02T03:00:00Z")
+        );
+        assert_eq!(
+            fixed_time(utc("2024-06-01T04:00:00Z"), 30, 3, 2, &tz),
+            utc("2024-06-04T03:30:00Z")
+        );
+    }
+
+    #[test]
+    fn fixed_time_local() {
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        // CET, UTC+1
+        assert_eq!(
+            fixed_time(utc("2024-01-15T12:00:00Z"), 0, 3, 0, &tz),
+            utc("2024-01-16T02:00:00Z")
+        );
+        // CEST, UTC+2
+        assert_eq!(
+            fixed_time(utc("2024-07-15T12:00:00Z"), 0, 3, 0, &tz),
+            utc("2024-07-16T01:00:00Z")
+        );
+        // Local time is already the next day
+        assert_eq!(
+            fixed_time(utc("2024-07-15T23:30:00Z"), 0, 3, 0, &tz),
+            utc("2024-07-16T01:00:00Z")
+        );
+    }
+
+    #[test]
+    fn fixed_time_across_transitions() {
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        // The night before the switch to CEST is an hour shorter
+        assert_eq!(
+            fixed_time(utc("2024-03-30T12:00:00Z"), 0, 3, 0, &tz),
+            utc("2024-03-31T01:00:00Z")
+        );
+        // The night before the switch to CET is an hour longer
+        assert_eq!(
+            fixed_time(utc("2024-10-26T12:00:00Z"), 0, 3, 0, &tz),
+            utc("2024-10-27T02:00:00Z")
+        );
+    }
+
+    #[test]
+    fn fixed_time_skipped() {
+        // 02:30 does not exist on 2024-03-31, clocks jump from 02:00 CET to 03:00 CEST
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        assert_eq!(
+            fixed_time(utc("2024-03-30T12:00:00Z"), 30, 2, 0, &tz),
+            utc("2024-03-31T01:00:00Z")
+        );
+        // 02:00 does not exist on 2024-03-10, clocks jump to 03:00 EDT
+        let tz: Tz = "America/New_York".parse().unwrap();
+        assert_eq!(
+            fixed_time(utc("2024-03-09T12:00:00Z"), 0, 2, 0, &tz),
+            utc("2024-03-10T07:00:00Z")
+        );
+    }
+
+    fn schedules(s: &[&str]) -> Vec<Schedule> {
+        s.iter().map(|x| x.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn scheduled_time_weekdays() {
+        let tz = Tz::UTC;
+        let s = schedules(&["0 3 * * mon-fri"]);
+        // Thursday evening
+        assert_eq!(
+            scheduled_time(utc("2024-06-06T20:00:00Z"), &s, &tz),
+            Some(utc("2024-06-07T03:00:00Z"))
+        );
+        // Friday evening lasts the whole weekend
+        assert_eq!(
+            scheduled_time(utc("2024-06-07T20:00:00Z"), &s, &tz),
+            Some(utc("2024-06-10T03:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn scheduled_time_multiple() {
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        let s = schedules(&["0 3,15 * * *", "30 12 * * sat"]);
+        assert_eq!(
+            scheduled_time(utc("2024-06-07T10:00:00Z"), &s, &tz),
+            Some(utc("2024-06-07T13:00:00Z"))
+        );
+        assert_eq!(
+            scheduled_time(utc("2024-06-08T09:00:00Z"), &s, &tz),
+            Some(utc("2024-06-08T10:30:00Z"))
+        );
+        // Exactly at a cutoff, the next one is used
+        assert_eq!(
+            scheduled_time(utc("2024-06-08T10:30:00Z"), &s, &tz),
+            Some(utc("2024-06-08T13:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn scheduled_time_day_of_month() {
+        let tz = Tz::UTC;
+        let s = schedules(&["0 0 29 2 *"]);
+        assert_eq!(
+            scheduled_time(utc("2024-03-01T00:00:00Z"), &s, &tz),
+            Some(utc("2028-02-29T00:00:00Z"))
+        );
+        // Either the day of the month or the weekday
+        let s = schedules(&["0 0 1 * sun"]);
+        assert_eq!(
+            scheduled_time(utc("2024-06-03T00:00:00Z"), &s, &tz),
+            Some(utc("2024-06-09T00:00:00Z"))
+        );
+        assert_eq!(
+            scheduled_time(utc("2024-06-30T12:00:00Z"), &s, &tz),
+            Some(utc("2024-07-01T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn scheduled_time_end_of_time() {
+        let s = schedules(&["0 0 * * *"]);
+        assert_eq!(scheduled_time(DateTime::<Utc>::MAX_UTC, &s, &Tz::UTC), None);
+        let policy = ExpiryPolicy::Schedule {
+            schedules: s,
+            timezone: Tz::UTC,
+        };
+        assert_eq!(
+            policy.valid_until(DateTime::<Utc>::MAX_UTC),
+            DateTime::<Utc>::MAX_UTC
+        );
+    }
+
+    #[test]
+    fn fixed_time_repeated() {
+        // 02:30 occurs twice on 2024-10-27, first in CEST (00:30Z) and then in CET (01:30Z)
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        assert_eq!(
+            fixed_time(utc("2024-10-26T12:00:00Z"), 30, 2, 0, &tz),
+            utc("2024-10-27T00:30:00Z")
+        );
+        // Between both occurrences
+        assert_eq!(
+            fixed_time(utc("2024-10-27T01:00:00Z"), 30, 2, 0, &tz),
+            utc("2024-10-27T01:30:00Z")
+        );
+        // After both occurrences
+        assert_eq!(
+            fixed_time(utc("2024-10-27T01:45:00Z"), 30, 2, 0, &tz),
+            utc("2024-10-28T01:30:00Z")
+        );
+    }
+
+    #[test]
+    fn fixed_days_limit() {
+        let settings = Settings::for_test("days = 100000000").unwrap();
+        let error = settings.validate().err().unwrap();
+        assert!(error.to_string().contains("days"), "{error}");
+        let settings =
+            Settings::for_test("days = 36500\ntimezone = \"Pacific/Kiritimati\"").unwrap();
+        let now = utc("2024-06-01T12:00:00Z");
+        let valid_until = ExpiryPolicy::new(&settings).valid_until(now);
+        assert_eq!(
+            valid_until.date_naive(),
+            utc("2124-05-08T00:00:00Z").date_naive()
+        );
+    }
+}
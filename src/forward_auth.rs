@@ -0,0 +1,297 @@
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tiny_http::{Header, HeaderField, Request, Response};
+use ureq::Agent;
+
+use crate::settings::Settings;
+
+/// Request headers that only concern the connection to ip-manager.
+const SKIPPED_HEADERS: [&str; 8] = [
+    "Host",
+    "Connection",
+    "Keep-Alive",
+    "Content-Length",
+    "Content-Type",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Expect",
+];
+
+/// Request headers naming the client, which are replaced by an `X-Forwarded-For` with the
+/// address ip-manager resolved, so the endpoint can't be given a spoofed one.
+const FORWARDING_HEADERS: [&str; 3] = ["X-Forwarded-For", "X-Real-IP", "Forwarded"];
+
+/// Response headers of a refusal that are passed on to the client, so it can log in.
+const RELAYED_HEADERS: [&str; 4] = ["Location", "Set-Cookie", "WWW-Authenticate", "Content-Type"];
+
+/// The result of asking the forward-auth endpoint.
+pub enum Verdict {
+    /// The request is authenticated, with the headers to save
+    Allowed(Vec<Header>),
+    /// The response of the endpoint, to pass on to the client
+    Refused(Response<Cursor<Vec<u8>>>),
+}
+
+fn agent() -> &'static Agent {
+    static AGENT: OnceLock<Agent> = OnceLock::new();
+    AGENT.get_or_init(|| {
+        Agent::config_builder()
+            .http_status_as_error(false)
+            .max_redirects(0)
+            .build()
+            .into()
+    })
+}
+
+/// Asks the `forward_auth_url` whether `rq` from the client at `addr` is authenticated.
+pub fn verify(
+    settings: &Settings,
+    url: &str,
+    addr: &IpAddr,
+    rq: &Request,
+) -> Result<Verdict, String> {
+    let timeout = Duration::from_secs(settings.forward_auth_timeout.into());
+    call(
+        url,
+        &request_headers(settings, addr, rq),
+        &settings.headers,
+        timeout,
+    )
+}
+
+/// The headers of `rq` to send to the forward-auth endpoint. The client headers in
+/// `client_ip_headers` and `FORWARDING_HEADERS` are replaced by `X-Forwarded-For: addr`.
+/// `X-Forwarded-Method` and `X-Forwarded-Uri` are added if the proxy didn't set them.
+fn request_headers(settings: &Settings, addr: &IpAddr, rq: &Request) -> Vec<(String, String)> {
+    let skipped: Vec<_> = SKIPPED_HEADERS
+        .iter()
+        .chain(&FORWARDING_HEADERS)
+        .map(|x| HeaderField::from_str(x).unwrap())
+        .chain(settings.client_ip_headers.iter().map(|x| x.field()))
+        .chain([settings.proxy_auth_header.clone()])
+        .collect();
+    let mut headers: Vec<_> = rq
+        .headers()
+        .iter()
+        .filter(|x| !skipped.contains(&x.field))
+        .map(|x| (x.field.to_string(), x.value.to_string()))
+        .chain([("X-Forwarded-For".into(), addr.to_string())])
+        .collect();
+    let value = |headers: &[(String, String)], name: &str| {
+        headers
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, x)| x.clone())
+    };
+    if value(&headers, "X-Forwarded-Method").is_none() {
+        headers.push(("X-Forwarded-Method".into(), rq.method().to_string()));
+    }
+    if value(&headers, "X-Forwarded-Uri").is_none() {
+        // Traefik's replacePath middleware saves the original path in X-Replaced-Path
+        let uri = value(&headers, "X-Replaced-Path").unwrap_or_else(|| rq.url().to_string());
+        headers.push(("X-Forwarded-Uri".into(), uri));
+    }
+    headers
+}
+
+/// Sends a `GET` request with `headers` to `url`. On a 2xx response the headers in `copy` are
+/// returned, otherwise the response itself.
+fn call(
+    url: &str,
+    headers: &[(String, String)],
+    copy: &[HeaderField],
+    timeout: Duration,
+) -> Result<Verdict, String> {
+    let mut request = agent().get(url);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    let mut response = request
+        .config()
+        .timeout_global(Some(timeout))
+        .build()
+        .call()
+        .map_err(|e| format!("Failed to call {url}: {e}"))?;
+
+    let status = response.status().as_u16();
+    // ureq lowercases the header names, so they are taken from the lists instead
+    let response_headers = |fields: &[HeaderField]| -> Vec<Header> {
+        fields
+            .iter()
+            .flat_map(|field| {
+                response
+                    .headers()
+                    .get_all(field.as_str().as_str())
+                    .iter()
+                    .filter_map(|x| Header::from_bytes(field.as_str().as_str(), x.as_bytes()).ok())
+            })
+            .collect()
+    };
+    if (200..300).contains(&status) {
+        return Ok(Verdict::Allowed(response_headers(copy)));
+    }
+
+    let relayed: Vec<_> = RELAYED_HEADERS
+        .iter()
+        .map(|x| HeaderField::from_str(x).unwrap())
+        .collect();
+    let relayed = response_headers(&relayed);
+    let body = response
+        .body_mut()
+        .with_config()
+        .limit(64 * 1024)
+        .read_to_vec()
+        .unwrap_or_default();
+    let mut refusal = Response::from_data(body).with_status_code(status);
+    for header in relayed {
+        refusal.add_header(header);
+    }
+    Ok(Verdict::Refused(refusal))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    use tiny_http::{Server, TestRequest};
+
+    use super::*;
+
+    /// Starts a stub forward-auth server that allows requests with the cookie `session=valid`.
+    fn stub() -> String {
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let url = format!("http://{}/api/verify", server.server_addr());
+        thread::spawn(move || {
+            for rq in server.incoming_requests() {
+                let cookie = HeaderField::from_str("Cookie").unwrap();
+                let valid = rq
+                    .headers()
+                    .iter()
+                    .any(|x| x.field == cookie && x.value == "session=valid");
+                let response = if valid {
+                    Response::from_string("")
+                        .with_header(Header::from_bytes("Remote-User", "alice").unwrap())
+                        .with_header(Header::from_bytes("Remote-Groups", "admins").unwrap())
+                        .with_header(Header::from_bytes("X-Other", "ignored").unwrap())
+                } else {
+                    Response::from_string("Unauthorized")
+                        .with_status_code(302)
+                        .with_header(Header::from_bytes("Location", "https://login").unwrap())
+                        .with_header(Header::from_bytes("X-Other", "ignored").unwrap())
+                };
+                let _ = rq.respond(response);
+            }
+        });
+        url
+    }
+
+    /// Starts a stub forward-auth server that allows all requests and sends the headers of each
+    /// one, with lowercase names, to the returned receiver.
+    fn recorder() -> (String, mpsc::Receiver<Vec<(String, String)>>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/verify", server.server_addr());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for rq in server.incoming_requests() {
+                let headers = rq
+                    .headers()
+                    .iter()
+                    .map(|x| (x.field.to_string().to_lowercase(), x.value.to_string()))
+                    .collect();
+                let _ = sender.send(headers);
+                let _ = rq.respond(Response::from_string(""));
+            }
+        });
+        (url, receiver)
+    }
+
+    fn field(name: &str) -> HeaderField {
+        HeaderField::from_str(name).unwrap()
+    }
+
+    #[test]
+    fn allowed() {
+        let url = stub();
+        let headers = [("Cookie".to_string(), "session=valid".to_string())];
+        let copy = [field("Remote-User"), field("Remote-Groups")];
+        let Ok(Verdict::Allowed(saved)) = call(&url, &headers, &copy, Duration::from_secs(5))
+        else {
+            panic!("request was not allowed");
+        };
+        let saved: Vec<_> = saved.iter().map(|x| x.to_string()).collect();
+        assert_eq!(saved, ["Remote-User: alice", "Remote-Groups: admins"]);
+    }
+
+    #[test]
+    fn refused() {
+        let url = stub();
+        let headers = [("Cookie".to_string(), "session=expired".to_string())];
+        let Ok(Verdict::Refused(response)) = call(&url, &headers, &[], Duration::from_secs(5))
+        else {
+            panic!("request was not refused");
+        };
+        assert_eq!(response.status_code().0, 302);
+        let relayed: Vec<_> = response.headers().iter().map(|x| x.to_string()).collect();
+        assert!(relayed.contains(&"Location: https://login".to_string()));
+        assert!(!relayed.iter().any(|x| x.starts_with("X-Other")));
+    }
+
+    #[test]
+    fn unreachable() {
+        // Nothing listens on the discard port
+        let url = "http://127.0.0.1:9/api/verify";
+        assert!(call(url, &[], &[], Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn forwarding_headers() {
+        let settings = Settings::for_test(
+            r#"
+            trusted_proxies = ["127.0.0.1"]
+            client_ip_headers = ["X-Client-IP", "X-Forwarded-For"]
+            proxy_secrets = ["secret"]
+            "#,
+        )
+        .unwrap();
+        let (url, received) = recorder();
+        let rq = [
+            ("X-Forwarded-For", "198.51.100.1, 192.0.2.1"),
+            ("X-Real-IP", "198.51.100.1"),
+            ("Forwarded", "for=198.51.100.1"),
+            ("X-Client-IP", "198.51.100.1"),
+            ("X-Proxy-Auth", "secret"),
+            ("Cookie", "session=valid"),
+        ]
+        .iter()
+        .fold(TestRequest::new().with_path("/authorize"), |rq, (k, v)| {
+            rq.with_header(Header::from_bytes(*k, *v).unwrap())
+        })
+        .into();
+        let addr = "192.0.2.1".parse().unwrap();
+        assert!(matches!(
+            verify(&settings, &url, &addr, &rq),
+            Ok(Verdict::Allowed(_))
+        ));
+
+        let headers = received.recv().unwrap();
+        let values = |name: &str| -> Vec<_> {
+            headers
+                .iter()
+                .filter(|(x, _)| x == name)
+                .map(|(_, x)| x.as_str())
+                .collect()
+        };
+        assert_eq!(values("x-forwarded-for"), ["192.0.2.1"]);
+        for name in ["x-real-ip", "forwarded", "x-client-ip", "x-proxy-auth"] {
+            assert!(values(name).is_empty(), "{name} was forwarded");
+        }
+        assert_eq!(values("cookie"), ["session=valid"]);
+        assert_eq!(values("x-forwarded-method"), ["GET"]);
+        assert_eq!(values("x-forwarded-uri"), ["/authorize"]);
+    }
+}
@@ -0,0 +1,347 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use log::warn;
+use tiny_http::{Header, HeaderField, Request};
+
+use crate::metrics::METRICS;
+use crate::proxy_protocol::Peers;
+use crate::settings::Settings;
+
+/// A header that a trusted proxy uses to pass on the address of the client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientIpHeader {
+    /// RFC 7239 `Forwarded`, using the `for` parameter of each element.
+    Forwarded,
+    /// Comma separated `X-Forwarded-For` list.
+    XForwardedFor,
+    /// Any other header containing a single address, like `X-Real-IP`.
+    Single(HeaderField),
+}
+
+impl FromStr for ClientIpHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let field =
+            HeaderField::from_str(s).map_err(|_| format!("\"{s}\" is not a valid header name"))?;
+        if field == HeaderField::from_str("Forwarded").unwrap() {
+            Ok(Self::Forwarded)
+        } else if field == HeaderField::from_str("X-Forwarded-For").unwrap() {
+            Ok(Self::XForwardedFor)
+        } else {
+            Ok(Self::Single(field))
+        }
+    }
+}
+
+impl ClientIpHeader {
+    pub fn field(&self) -> HeaderField {
+        match self {
+            Self::Forwarded => HeaderField::from_str("Forwarded").unwrap(),
+            Self::XForwardedFor => HeaderField::from_str("X-Forwarded-For").unwrap(),
+            Self::Single(x) => x.clone(),
+        }
+    }
+
+    /// The addresses in the header, from the client to the last proxy. `None` if the request does
+    /// not contain the header.
+    fn chain<'a>(&self, headers: &'a [Header]) -> Option<Vec<&'a str>> {
+        let field = self.field();
+        let mut values = headers
+            .iter()
+            .filter(|x| x.field == field)
+            .map(|x| x.value.as_str())
+            .peekable();
+        values.peek()?;
+        let chain = match self {
+            Self::Forwarded => values.flat_map(forwarded_for).collect(),
+            Self::XForwardedFor => values.flat_map(|x| x.split(',')).map(str::trim).collect(),
+            Self::Single(_) => values.last().map(str::trim).into_iter().collect(),
+        };
+        Some(chain)
+    }
+}
+
+/// Determines the address of the client that sent `rq`.
+///
+/// For connections received with the PROXY protocol, the address from its header is used as the
+/// peer address. Forwarding headers are only honored if the peer is a trusted proxy. The first header
+/// from `client_ip_headers` present in the request is walked from the right, skipping over trusted
+/// proxies, until the first untrusted address or `forwarded_hops` entries have been consumed.
+///
+/// IPv4-mapped IPv6 addresses are returned as plain IPv4 addresses.
+pub fn client_ip(settings: &Settings, peers: &Peers, rq: &Request) -> IpAddr {
+    let remote = rq.remote_addr().unwrap();
+    let peer = peers.get(remote).unwrap_or(remote.ip());
+    find_client_ip(settings, peer, rq.headers()).to_canonical()
+}
+
+fn find_client_ip(settings: &Settings, peer: IpAddr, headers: &[Header]) -> IpAddr {
+    let mut client = peer.to_canonical();
+    let Some((header, chain)) = settings
+        .client_ip_headers
+        .iter()
+        .find_map(|x| x.chain(headers).map(|chain| (x, chain)))
+    else {
+        return client;
+    };
+    if !settings.trusted_proxies.contains(&client) {
+        warn!(
+            "Ignoring {} header from {client}, which is not in trusted_proxies",
+            header.field()
+        );
+        return client;
+    }
+
+    for (hop, entry) in chain.iter().rev().enumerate() {
+        if settings.forwarded_hops.is_some_and(|x| hop >= x)
+            || !settings.trusted_proxies.contains(&client)
+        {
+            break;
+        }
+        match parse_node(entry) {
+            Some(ip) => client = ip.to_canonical(),
+            None => {
+                warn!(
+                    "Got request with invalid {} entry: \"{entry}\"",
+                    header.field()
+                );
+                METRICS.invalid_forwarded_header();
+                break;
+            }
+        }
+    }
+    client
+}
+
+/// Extracts the `for` parameter of every element of a `Forwarded` header value.
+fn forwarded_for(value: &str) -> Vec<&str> {
+    split_quoted(value, ',')
+        .into_iter()
+        .map(|element| {
+            split_quoted(element, ';')
+                .into_iter()
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .map_or("", |(_, value)| value.trim())
+        })
+        .collect()
+}
+
+/// Splits `s` at `separator`, ignoring separators inside quoted strings.
+fn split_quoted(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(s[start..i].trim());
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(s[start..].trim());
+    parts
+}
+
+/// Parses a node as found in forwarding headers: a plain address, optionally quoted, with an
+/// optional port and IPv6 addresses optionally in brackets. Obfuscated identifiers and `unknown`
+/// are not addresses and result in `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    let node = node
+        .strip_prefix('"')
+        .and_then(|x| x.strip_suffix('"'))
+        .unwrap_or(node);
+    if let Ok(ip) = IpAddr::from_str(node) {
+        return Some(ip);
+    }
+    if let Ok(addr) = SocketAddr::from_str(node) {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .and_then(|x| IpAddr::from_str(x).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(headers: &[(&str, &str)]) -> Vec<Header> {
+        headers
+            .iter()
+            .map(|(k, v)| Header::from_bytes(*k, *v).unwrap())
+            .collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_nodes() {
+        assert_eq!(parse_node("192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node(" 192.0.2.1:8080 "), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(
+            parse_node("\"[2001:db8::1]:4711\""),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(parse_node("\"192.0.2.1\""), Some(ip("192.0.2.1")));
+        for x in [
+            "unknown",
+            "_hidden",
+            "\"_SEVKISEK\"",
+            "",
+            "2001:db8::1:4711:",
+        ] {
+            assert_eq!(parse_node(x), None, "{x}");
+        }
+    }
+
+    #[test]
+    fn forwarded_elements() {
+        assert_eq!(
+            forwarded_for("for=192.0.2.43, for=198.51.100.17;proto=https"),
+            ["192.0.2.43", "198.51.100.17"]
+        );
+        assert_eq!(
+            forwarded_for("For=\"[2001:db8:cafe::17]:4711\"; proto=http"),
+            ["\"[2001:db8:cafe::17]:4711\""]
+        );
+        assert_eq!(
+            forwarded_for("for=unknown, for=_hidden;by=_proxy"),
+            ["unknown", "_hidden"]
+        );
+        // Separators in quoted strings, and elements without a for parameter
+        assert_eq!(
+            forwarded_for(r#"by="a,b;for=1.2.3.4";for=192.0.2.1;host="x\",y", proto=https"#),
+            ["192.0.2.1", ""]
+        );
+    }
+
+    #[test]
+    fn split_quoted_strings() {
+        assert_eq!(split_quoted("a, b ,c", ','), ["a", "b", "c"]);
+        assert_eq!(split_quoted("a=\"x,y\",b", ','), ["a=\"x,y\"", "b"]);
+        assert_eq!(split_quoted(r#"a="x\";y";b"#, ';'), [r#"a="x\";y""#, "b"]);
+        assert_eq!(split_quoted("", ','), [""]);
+    }
+
+    #[test]
+    fn forwarded_header() {
+        let s = Settings::for_test(
+            r#"
+            trusted_proxies = ["10.0.0.0/8"]
+            client_ip_headers = ["Forwarded"]
+            "#,
+        )
+        .unwrap();
+        let forwarded = headers(&[(
+            "Forwarded",
+            "for=198.51.100.1, for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2",
+        )]);
+        assert_eq!(
+            find_client_ip(&s, ip("10.0.0.1"), &forwarded),
+            ip("2001:db8::1")
+        );
+        // An obfuscated node ends the walk at the last trusted proxy
+        let forwarded = headers(&[("Forwarded", "for=192.0.2.1, for=_hidden")]);
+        assert_eq!(
+            find_client_ip(&s, ip("10.0.0.1"), &forwarded),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn untrusted_peer() {
+        let s = Settings::for_test(r#"trusted_proxies = ["10.0.0.0/8"]"#).unwrap();
+        let xff = headers(&[("X-Forwarded-For", "192.0.2.1")]);
+        assert_eq!(
+            find_client_ip(&s, ip("203.0.113.5"), &xff),
+            ip("203.0.113.5")
+        );
+        assert_eq!(
+            find_client_ip(&s, ip("203.0.113.5"), &[]),
+            ip("203.0.113.5")
+        );
+    }
+
+    #[test]
+    fn trusted_peer() {
+        let s = Settings::for_test(r#"trusted_proxies = ["10.0.0.0/8", "::1"]"#).unwrap();
+        let xff = headers(&[("X-Forwarded-For", "192.0.2.1")]);
+        assert_eq!(find_client_ip(&s, ip("10.0.0.1"), &xff), ip("192.0.2.1"));
+        assert_eq!(find_client_ip(&s, ip("::1"), &xff), ip("192.0.2.1"));
+        assert_eq!(
+            find_client_ip(&s, ip("::ffff:10.0.0.1"), &xff),
+            ip("192.0.2.1")
+        );
+        assert_eq!(find_client_ip(&s, ip("10.0.0.1"), &[]), ip("10.0.0.1"));
+        // A client can't pass itself off as someone else by sending its own header
+        let xff = headers(&[("X-Forwarded-For", "198.51.100.1, 192.0.2.1, 10.0.0.2")]);
+        assert_eq!(find_client_ip(&s, ip("10.0.0.1"), &xff), ip("192.0.2.1"));
+        // Several headers form one list
+        let xff = headers(&[
+            ("X-Forwarded-For", "198.51.100.1"),
+            ("X-Forwarded-For", "192.0.2.1"),
+        ]);
+        assert_eq!(find_client_ip(&s, ip("10.0.0.1"), &xff), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn forwarded_hops() {
+        let s =
+            Settings::for_test("trusted_proxies = [\"10.0.0.0/8\"]\nforwarded_hops = 1").unwrap();
+        let xff = headers(&[("X-Forwarded-For", "192.0.2.1, 10.0.0.2")]);
+        assert_eq!(find_client_ip(&s, ip("10.0.0.1"), &xff), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn invalid_entry() {
+        let s = Settings::for_test(r#"trusted_proxies = ["10.0.0.0/8"]"#).unwrap();
+        let xff = headers(&[("X-Forwarded-For", "192.0.2.1, garbage")]);
+        assert_eq!(find_client_ip(&s, ip("10.0.0.1"), &xff), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn header_order() {
+        let s = Settings::for_test(
+            r#"
+            trusted_proxies = ["10.0.0.0/8"]
+            client_ip_headers = ["X-Real-IP", "X-Forwarded-For"]
+            "#,
+        )
+        .unwrap();
+        let both = headers(&[
+            ("X-Forwarded-For", "198.51.100.1"),
+            ("X-Real-IP", "192.0.2.1"),
+        ]);
+        assert_eq!(find_client_ip(&s, ip("10.0.0.1"), &both), ip("192.0.2.1"));
+        let xff = headers(&[("X-Forwarded-For", "198.51.100.1")]);
+        assert_eq!(find_client_ip(&s, ip("10.0.0.1"), &xff), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn trusted_proxies_required() {
+        for toml in [
+            "client_ip_headers = [\"X-Forwarded-For\"]",
+            "proxy_protocol = true\nclient_ip_headers = []",
+        ] {
+            let error = Settings::for_test(toml).err().unwrap().to_string();
+            assert!(error.contains("trusted_proxies is required"), "{error}");
+        }
+        let s = Settings::for_test("client_ip_headers = []").unwrap();
+        let xff = headers(&[("X-Forwarded-For", "192.0.2.1")]);
+        assert_eq!(find_client_ip(&s, ip("127.0.0.1"), &xff), ip("127.0.0.1"));
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+use tiny_http::Request;
+
+use crate::admin::{json, JsonResponse};
+use crate::IpWhitelist;
+
+/// State of the process checked by `/readyz`.
+pub struct Health {
+    state_loaded: AtomicBool,
+    pruner_alive: AtomicBool,
+}
+
+pub static HEALTH: Health = Health::new();
+
+impl Health {
+    const fn new() -> Self {
+        Self {
+            state_loaded: AtomicBool::new(false),
+            pruner_alive: AtomicBool::new(false),
+        }
+    }
+
+    pub fn state_loaded(&self) {
+        self.state_loaded.store(true, Ordering::Relaxed);
+    }
+
+    /// Marks the pruner as running until the returned guard is dropped, which also happens when
+    /// the pruner thread panics.
+    pub fn pruner(&self) -> PrunerGuard<'_> {
+        self.pruner_alive.store(true, Ordering::Relaxed);
+        PrunerGuard(self)
+    }
+}
+
+pub struct PrunerGuard<'a>(&'a Health);
+
+impl Drop for PrunerGuard<'_> {
+    fn drop(&mut self) {
+        self.0.pruner_alive.store(false, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    checks: Checks,
+}
+
+#[derive(Serialize)]
+struct Checks {
+    state_loaded: bool,
+    pruner_alive: bool,
+    whitelist_usable: bool,
+}
+
+/// Responds to `/healthz` as long as the process is serving requests.
+pub fn healthz(rq: Request) {
+    let _ = rq.respond(json(200, &Status { status: "ok" }));
+}
+
+/// Responds to `/readyz` with 200 if all checks pass and 503 otherwise.
+pub fn readyz(whitelist: &IpWhitelist, rq: Request) {
+    let _ = rq.respond(readiness(&HEALTH, whitelist));
+}
+
+fn readiness(health: &Health, whitelist: &IpWhitelist) -> JsonResponse {
+    let checks = Checks {
+        state_loaded: health.state_loaded.load(Ordering::Relaxed),
+        pruner_alive: health.pruner_alive.load(Ordering::Relaxed),
+        whitelist_usable: !whitelist.list.is_poisoned(),
+    };
+    let ready = checks.state_loaded && checks.pruner_alive && checks.whitelist_usable;
+    let readiness = Readiness {
+        status: if ready { "ready" } else { "not ready" },
+        checks,
+    };
+    json(if ready { 200 } else { 503 }, &readiness)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::thread;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::expiry::ExpiryPolicy;
+    use crate::settings::Settings;
+
+    /// The status code and the failed checks.
+    fn check(health: &Health, whitelist: &IpWhitelist) -> (u16, Vec<String>) {
+        let response = readiness(health, whitelist);
+        let status = response.status_code().0;
+        let mut body = String::new();
+        response.into_reader().read_to_string(&mut body).unwrap();
+        let body: Value = serde_json::from_str(&body).unwrap();
+        let failed = body["checks"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .filter(|(_, v)| **v == Value::Bool(false))
+            .map(|(k, _)| k.clone())
+            .collect();
+        (status, failed)
+    }
+
+    #[test]
+    fn readiness_checks() {
+        let settings = Settings::for_test("").unwrap();
+        let whitelist = IpWhitelist::build(ExpiryPolicy::new(&settings), 64, None);
+        let health = Health::new();
+        assert_eq!(
+            check(&health, &whitelist),
+            (503, vec!["pruner_alive".into(), "state_loaded".into()])
+        );
+        health.state_loaded();
+        assert_eq!(
+            check(&health, &whitelist),
+            (503, vec!["pruner_alive".into()])
+        );
+        let guard = health.pruner();
+        assert_eq!(check(&health, &whitelist), (200, vec![]));
+        drop(guard);
+        assert_eq!(
+            check(&health, &whitelist),
+            (503, vec!["pruner_alive".into()])
+        );
+
+        let _guard = health.pruner();
+        thread::scope(|s| {
+            let poisoned = s.spawn(|| {
+                let _list = whitelist.list.write().unwrap();
+                panic!("Poisoning the whitelist");
+            });
+            assert!(poisoned.join().is_err());
+        });
+        assert_eq!(
+            check(&health, &whitelist),
+            (503, vec!["whitelist_usable".into()])
+        );
+    }
+}
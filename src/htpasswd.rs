@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::prelude::*;
+use tiny_http::{HeaderField, Request};
+
+/// Number of failed logins after which a client has to wait for the end of `FAILURE_WINDOW`.
+const MAX_FAILURES: u32 = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Failed logins per client and user, so a client can't keep the request threads busy with
+/// hashing or guess TOTP codes.
+static FAILURES: Mutex<Failures> = Mutex::new(Failures(BTreeMap::new()));
+
+/// A user of the `htpasswd_file` whose password was verified.
+pub struct User {
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+/// The user name and password from the `Authorization: Basic` header of `rq`.
+pub fn credentials(rq: &Request) -> Option<(String, String)> {
+    let field = HeaderField::from_str("Authorization").unwrap();
+    let value = rq
+        .headers()
+        .iter()
+        .find(|x| x.field == field)?
+        .value
+        .as_str();
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    Some((name.to_string(), password.to_string()))
+}
+
+/// Checks `password` against the entry of `name` in the htpasswd file at `path`. The file
+/// contains lines of `name:hash` or `name:hash:group,group`, with bcrypt or argon2 hashes.
+pub fn verify(path: &str, name: &str, password: &str) -> Result<Option<User>, String> {
+    let file = fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+    let entry = file
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| x.splitn(3, ':').collect::<Vec<_>>())
+        .find(|x| x[0] == name);
+    let Some(entry) = entry else {
+        // Take as long as for an existing user, so user names can't be guessed by the timing
+        let _ = bcrypt::verify(password, dummy_hash());
+        return Ok(None);
+    };
+    let hash = entry.get(1).copied().unwrap_or_default();
+    let valid = if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).map_err(|e| format!("Invalid hash of {name}: {e}"))?
+    } else if hash.starts_with("$argon2") {
+        let hash = PasswordHash::new(hash).map_err(|e| format!("Invalid hash of {name}: {e}"))?;
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    } else {
+        return Err(format!(
+            "Unsupported hash of {name}, expected bcrypt or argon2"
+        ));
+    };
+    if !valid {
+        return Ok(None);
+    }
+    let groups = entry
+        .get(2)
+        .map(|x| {
+            x.split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(Some(User {
+        name: name.to_string(),
+        groups,
+    }))
+}
+
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| bcrypt::hash("", bcrypt::DEFAULT_COST).unwrap())
+}
+
+/// What failed logins are counted for. Wrong passwords count for the client, wrong TOTP codes
+/// also for the user, so the codes of a user can't be guessed from many clients.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Login {
+    Client(IpAddr),
+    User(String),
+}
+
+/// Whether one of `logins` has failed too often and must not try again yet.
+pub fn throttled(logins: &[Login]) -> bool {
+    let failures = FAILURES.lock().expect("Failed logins are poisoned");
+    logins.iter().any(|x| failures.throttled(x, Instant::now()))
+}
+
+/// Counts a failed login of each of `logins`. Returns true if one is throttled from now on.
+pub fn failed(logins: &[Login]) -> bool {
+    let mut failures = FAILURES.lock().expect("Failed logins are poisoned");
+    let now = Instant::now();
+    // Not `any`, which would stop counting at the first throttled login
+    let throttled: Vec<_> = logins.iter().map(|x| failures.failed(x, now)).collect();
+    throttled.contains(&true)
+}
+
+/// Forgets the failed logins of `logins` after a successful one.
+pub fn succeeded(logins: &[Login]) {
+    let mut failures = FAILURES.lock().expect("Failed logins are poisoned");
+    for x in logins {
+        failures.0.remove(x);
+    }
+}
+
+/// The number of failed logins of each client or user since the start of its current window.
+struct Failures(BTreeMap<Login, (u32, Instant)>);
+
+impl Failures {
+    fn throttled(&self, login: &Login, now: Instant) -> bool {
+        self.0
+            .get(login)
+            .is_some_and(|(count, start)| *count >= MAX_FAILURES && now - *start < FAILURE_WINDOW)
+    }
+
+    fn failed(&mut self, login: &Login, now: Instant) -> bool {
+        self.0.retain(|_, (_, start)| now - *start < FAILURE_WINDOW);
+        let (count, _) = self.0.entry(login.clone()).or_insert((0, now));
+        *count += 1;
+        *count >= MAX_FAILURES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::{env, process};
+
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use tiny_http::{Header, TestRequest};
+
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> Request {
+        let rq = TestRequest::new().with_path("/authorize");
+        match authorization {
+            Some(x) => rq.with_header(Header::from_bytes("Authorization", x).unwrap()),
+            None => rq,
+        }
+        .into()
+    }
+
+    fn file(name: &str, content: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ip-manager-{}-{name}", process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn basic_credentials() {
+        let basic = |x: &str| request(Some(&format!("Basic {}", BASE64_STANDARD.encode(x))));
+        assert_eq!(
+            credentials(&basic("alice:secret")),
+            Some(("alice".into(), "secret".into()))
+        );
+        // Only the first colon separates the name from the password
+        assert_eq!(
+            credentials(&basic("alice:a:b")),
+            Some(("alice".into(), "a:b".into()))
+        );
+        assert_eq!(
+            credentials(&basic(":")),
+            Some((String::new(), String::new()))
+        );
+        assert_eq!(credentials(&basic("alice")), None);
+        assert_eq!(credentials(&request(Some("Basic not base64!"))), None);
+        let invalid_utf8 = BASE64_STANDARD.encode(b"alice:\xff");
+        assert_eq!(
+            credentials(&request(Some(&format!("Basic {invalid_utf8}")))),
+            None
+        );
+        assert_eq!(credentials(&request(Some("Bearer YWxpY2U6c2VjcmV0"))), None);
+        assert_eq!(credentials(&request(None)), None);
+    }
+
+    #[test]
+    fn verify_users() {
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        let salt = SaltString::encode_b64(b"ip-manager salt").unwrap();
+        let argon2 = Argon2::default()
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        let path = file(
+            "htpasswd",
+            &format!(
+                "# comment\nalice:{bcrypt}\n\n  bob:{argon2}:admins, ,users\ncarol:{{SHA}}abc=\n"
+            ),
+        );
+        let path = path.to_str().unwrap();
+
+        let alice = verify(path, "alice", "secret").unwrap().unwrap();
+        assert_eq!(alice.name, "alice");
+        assert!(alice.groups.is_empty());
+        assert!(verify(path, "alice", "Secret").unwrap().is_none());
+        let bob = verify(path, "bob", "hunter2").unwrap().unwrap();
+        assert_eq!(bob.groups, ["admins", "users"]);
+        assert!(verify(path, "bob", "secret").unwrap().is_none());
+        assert!(verify(path, "dave", "secret").unwrap().is_none());
+        assert!(verify(path, "# comment", "").unwrap().is_none());
+        let error = verify(path, "carol", "secret").err().unwrap();
+        assert_eq!(
+            error,
+            "Unsupported hash of carol, expected bcrypt or argon2"
+        );
+        assert!(verify("/nonexistent/htpasswd", "alice", "secret").is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn throttle_failures() {
+        let mut failures = Failures(BTreeMap::new());
+        let client = Login::Client("192.0.2.1".parse().unwrap());
+        let other = Login::User("alice".into());
+        let start = Instant::now();
+        for _ in 1..MAX_FAILURES {
+            assert!(!failures.failed(&client, start));
+        }
+        assert!(!failures.throttled(&client, start));
+        assert!(failures.failed(&client, start + Duration::from_secs(30)));
+        assert!(failures.throttled(&client, start + Duration::from_secs(30)));
+        assert!(!failures.throttled(&other, start + Duration::from_secs(30)));
+        // The window starts at the first failure
+        let end = start + FAILURE_WINDOW;
+        assert!(failures.throttled(&client, end - Duration::from_secs(1)));
+        assert!(!failures.throttled(&client, end));
+        assert!(!failures.failed(&other, end));
+        assert_eq!(failures.0.len(), 1);
+    }
+}
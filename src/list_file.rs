@@ -0,0 +1,204 @@
+use std::collections::HashSet;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::{error, info};
+
+use crate::net::{IpNet, PrefixSet};
+use crate::settings::{Settings, SharedSettings};
+
+/// How often the list files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The contents of `allow_list_file` and `deny_list_file`. They are shared by all versions of
+/// the settings, so a reload of the config keeps them.
+#[derive(Debug, Default)]
+pub struct ListFiles {
+    pub allow: ListFile,
+    pub deny: ListFile,
+}
+
+/// A list of addresses and networks read from a file.
+#[derive(Debug, Default)]
+pub struct ListFile {
+    current: RwLock<Contents>,
+}
+
+#[derive(Debug, Default)]
+struct Contents {
+    /// Path and modification time of the file the entries were read from
+    source: Option<(PathBuf, SystemTime)>,
+    /// Path and modification time of the last version that failed to parse
+    rejected: Option<(PathBuf, SystemTime)>,
+    entries: Vec<IpNet>,
+    set: PrefixSet,
+}
+
+impl ListFile {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        self.current
+            .read()
+            .expect("List file is poisoned")
+            .set
+            .contains(addr)
+    }
+
+    /// Reads `path` again if it changed since the last call. Without a path the list is empty.
+    /// If the file can't be read, the current entries are kept.
+    fn update(&self, name: &str, path: Option<&str>) -> Result<(), String> {
+        let Some(path) = path.map(Path::new) else {
+            let mut current = self.current.write().expect("List file is poisoned");
+            if current.source.is_some() {
+                info!("{name} was removed from the config, removing all its entries");
+                *current = Contents::default();
+            }
+            return Ok(());
+        };
+        let error = |e| format!("Failed to read {name} {}: {e}", path.display());
+        let modified = fs::metadata(path)
+            .and_then(|x| x.modified())
+            .map_err(error)?;
+        let version = (path.to_path_buf(), modified);
+        let seen = {
+            let current = self.current.read().expect("List file is poisoned");
+            [&current.source, &current.rejected].contains(&&Some(version.clone()))
+        };
+        if seen {
+            return Ok(());
+        }
+
+        let entries = parse(&fs::read_to_string(path).map_err(error)?);
+        let mut current = self.current.write().expect("List file is poisoned");
+        let entries = match entries {
+            Ok(x) => x,
+            Err(e) => {
+                current.rejected = Some(version);
+                return Err(format!("Failed to parse {name} {}: {e}", path.display()));
+            }
+        };
+        let old: HashSet<_> = current.entries.iter().collect();
+        let new: HashSet<_> = entries.iter().collect();
+        for x in entries.iter().filter(|x| !old.contains(x)) {
+            info!("{name}: added {x}");
+        }
+        for x in current.entries.iter().filter(|x| !new.contains(x)) {
+            info!("{name}: removed {x}");
+        }
+        *current = Contents {
+            source: Some(version),
+            rejected: None,
+            set: entries.iter().copied().collect(),
+            entries,
+        };
+        Ok(())
+    }
+}
+
+impl ListFiles {
+    /// Reads the list files configured in `settings` if they changed.
+    pub fn update(&self, settings: &Settings) -> Result<(), String> {
+        let errors: Vec<_> = [
+            self.allow
+                .update("allow_list_file", settings.allow_list_file.as_deref()),
+            self.deny
+                .update("deny_list_file", settings.deny_list_file.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(Result::err)
+        .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+/// Parses one address or network per line. Everything after a `#` is a comment.
+fn parse(s: &str) -> Result<Vec<IpNet>, String> {
+    s.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| IpNet::from_str(line).map_err(|e| format!("line {}: {e}", i + 1)))
+        .collect()
+}
+
+/// Checks the list files for changes until the process exits.
+pub fn watch(shared: Arc<SharedSettings>) {
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let settings = shared.load();
+        if let Err(e) = settings.list_files.update(&settings) {
+            error!("{e}, keeping the current list");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// Writes `content` to `path` with a modification time of `secs` after the epoch, so every
+    /// version is seen as a change.
+    fn write(path: &Path, content: &str, secs: u64) {
+        fs::write(path, content).unwrap();
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_lines() {
+        let entries = parse("# office\n192.0.2.1\n\n  2001:db8::/32  # vpn\n\t\n10.0.0.0/8#lan\n");
+        let entries: Vec<_> = entries.unwrap().iter().map(|x| x.to_string()).collect();
+        assert_eq!(entries, ["192.0.2.1/32", "2001:db8::/32", "10.0.0.0/8"]);
+        assert_eq!(parse("").unwrap(), []);
+        let error = parse("192.0.2.1\n# comment\n\n192.0.2.300\n").unwrap_err();
+        assert!(error.starts_with("line 4: "), "{error}");
+    }
+
+    #[test]
+    fn update() {
+        let path = env::temp_dir().join(format!("ip-manager-{}-list", process::id()));
+        let file = path.to_str();
+        let list = ListFile::default();
+        write(&path, "192.0.2.1\n", 1);
+        list.update("allow_list_file", file).unwrap();
+        assert!(list.contains(&ip("192.0.2.1")));
+
+        // A version that fails to parse keeps the old entries and is only reported once
+        write(&path, "192.0.2.2\nnot an address\n", 2);
+        let error = list.update("allow_list_file", file).unwrap_err();
+        assert!(error.contains("line 2"), "{error}");
+        assert!(list.contains(&ip("192.0.2.1")));
+        assert!(!list.contains(&ip("192.0.2.2")));
+        list.update("allow_list_file", file).unwrap();
+        assert!(list.contains(&ip("192.0.2.1")));
+
+        write(&path, "192.0.2.2\n", 3);
+        list.update("allow_list_file", file).unwrap();
+        assert!(!list.contains(&ip("192.0.2.1")));
+        assert!(list.contains(&ip("192.0.2.2")));
+
+        // A file that can't be read keeps the entries too
+        fs::remove_file(&path).unwrap();
+        assert!(list.update("allow_list_file", file).is_err());
+        assert!(list.contains(&ip("192.0.2.2")));
+
+        // Removing the path from the config clears the list
+        list.update("allow_list_file", None).unwrap();
+        assert!(!list.contains(&ip("192.0.2.2")));
+    }
+}
@@ -1,188 +1,594 @@
+mod admin;
+mod cli;
+mod expiry;
+mod forward_auth;
+mod forwarded;
+mod health;
+mod htpasswd;
+mod list_file;
+mod metrics;
+mod net;
+mod proxy_auth;
+mod proxy_protocol;
+mod schedule;
 mod settings;
+mod state;
+mod totp;
 use chrono::prelude::*;
-use chrono::Days;
+use clap::Parser;
+use cli::{Cli, Command};
 use chrono::TimeDelta;
-use ctrlc;
+use expiry::ExpiryPolicy;
+use forward_auth::Verdict;
+use health::HEALTH;
+use htpasswd::Login;
 use log::{debug, error, info, trace, warn};
-use settings::Settings;
+use metrics::{Endpoint, Reason, METRICS};
+use proxy_protocol::Peers;
+use settings::{Settings, SharedSettings};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::io::{self, Read};
+use std::net::{IpAddr, TcpListener};
+use state::{Event, Store};
+use std::path::PathBuf;
 use std::process::ExitCode;
-use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::thread;
-use std::time::Duration;
-use tiny_http::{Header, HeaderField, Request, Response, Server};
-use validator::Validate;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response, Server};
 
 #[derive(Clone)]
 struct WhitelistElement {
+    authorized_at: DateTime<Utc>,
     valid_until: DateTime<Utc>,
     headers: Vec<Header>,
 }
 
 struct IpWhitelist {
     list: RwLock<HashMap<IpAddr, WhitelistElement>>,
-    minute: u8,
-    hour: u8,
-    days: u32,
+    policy: RwLock<ExpiryPolicy>,
+    ipv6_prefix_length: u8,
+    store: Option<Store>,
 }
 
 impl IpWhitelist {
-    fn build(minute: u8, hour: u8, days: u32) -> Self {
+    fn build(policy: ExpiryPolicy, ipv6_prefix_length: u8, store: Option<Store>) -> Self {
         Self {
             list: RwLock::new(HashMap::new()),
-            minute,
-            hour,
-            days,
+            policy: RwLock::new(policy),
+            ipv6_prefix_length,
+            store,
         }
     }
 
-    fn is_allowed(&self, addr: &IpAddr) -> Result<Vec<Header>, ()> {
+    /// The key under which `addr` is stored. IPv4-mapped addresses are converted to IPv4 and IPv6
+    /// addresses are truncated to `ipv6_prefix_length`, so the whole prefix shares one entry.
+    fn key(&self, addr: &IpAddr) -> IpAddr {
+        match addr.to_canonical() {
+            x @ IpAddr::V4(_) => x,
+            x @ IpAddr::V6(_) => net::truncate(&x, self.ipv6_prefix_length),
+        }
+    }
+
+    /// Loads the saved whitelist, dropping entries that expired in the meantime.
+    fn restore(&self) -> io::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let now = Utc::now();
+        let saved: HashMap<_, _> = store
+            .load()?
+            .into_iter()
+            .filter(|(_, v)| v.valid_until.signed_duration_since(now) > TimeDelta::zero())
+            .map(|(k, v)| (self.key(&k), v))
+            .collect();
+        info!(
+            "Restored {} authorizations from {}",
+            saved.len(),
+            store.path().display()
+        );
+        *self.list.write().expect("Whitelist is poisoned") = saved;
+        Ok(())
+    }
+
+    fn record(&self, event: Event) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.record(event) {
+                error!("Failed to write journal: {e}");
+            }
+        }
+    }
+
+    /// Rewrites the snapshot from the current whitelist and empties the journal.
+    fn compact(&self) -> io::Result<()> {
+        match &self.store {
+            Some(store) => store.compact(&self.list.write().expect("Whitelist is poisoned")),
+            None => Ok(()),
+        }
+    }
+
+    fn is_allowed(&self, addr: &IpAddr) -> Result<Vec<Header>, Reason> {
         if let Some(x) = self.get_ip(addr) {
-            trace!("{:#}",x.valid_until.signed_duration_since(Utc::now()));
-            if x.valid_until.signed_duration_since(Utc::now()) > TimeDelta::zero() {
+            let now = Utc::now();
+            trace!("{:#}",x.valid_until.signed_duration_since(now));
+            if x.valid_until.signed_duration_since(now) > TimeDelta::zero() {
+                let refresh = self.policy.read().expect("Expiry policy is poisoned").refresh(
+                    x.authorized_at,
+                    x.valid_until,
+                    now,
+                );
+                if let Some(valid_until) = refresh {
+                    self.extend(addr, valid_until);
+                }
                 Ok(x.headers.clone())
             } else {
                 debug!("Expired IP: {addr}");
                 self.delete_ip(addr);
-                Err(())
+                Err(Reason::Expired)
             }
         } else {
-            Err(())
+            Err(Reason::Unknown)
         }
     }
 
     fn get_ip(&self, addr: &IpAddr) -> Option<WhitelistElement> {
-        if let Some(x) = self.list.read().expect("Whitelist is poisoned").get(addr) {
-            Some(x.clone())
-        } else {
-            None
-        }
+        self.list
+            .read()
+            .expect("Whitelist is poisoned")
+            .get(&self.key(addr))
+            .cloned()
     }
 
     fn delete_ip(&self, addr: &IpAddr) {
+        let addr = self.key(addr);
+        let mut list = self.list.write().expect("Whitelist is poisoned");
+        if list.remove(&addr).is_some() {
+            self.record(Event::Expire(&addr));
+        }
+    }
+
+    /// Moves the expiry of an existing authorization to `valid_until`.
+    fn extend(&self, addr: &IpAddr, valid_until: DateTime<Utc>) {
+        self.update(addr, |x| x.valid_until = valid_until);
+    }
+
+    /// Changes an existing authorization and returns it with its key.
+    fn update(
+        &self,
+        addr: &IpAddr,
+        f: impl FnOnce(&mut WhitelistElement),
+    ) -> Option<(IpAddr, WhitelistElement)> {
+        let addr = self.key(addr);
+        let mut list = self.list.write().expect("Whitelist is poisoned");
+        let x = list.get_mut(&addr)?;
+        f(x);
+        self.record(Event::Allow(&addr, x));
+        Some((addr, x.clone()))
+    }
+
+    fn len(&self) -> usize {
+        self.list.read().expect("Whitelist is poisoned").len()
+    }
+
+    fn entries(&self) -> Vec<(IpAddr, WhitelistElement)> {
         self.list
-            .write()
+            .read()
             .expect("Whitelist is poisoned")
-            .remove(addr);
+            .iter()
+            .map(|(k, v)| (*k, v.clone()))
+            .collect()
+    }
+
+    /// Removes the authorization of `addr` and returns its key if there was one.
+    fn revoke(&self, addr: &IpAddr) -> Option<IpAddr> {
+        let addr = self.key(addr);
+        let mut list = self.list.write().expect("Whitelist is poisoned");
+        list.remove(&addr)?;
+        self.record(Event::Revoke(&addr));
+        Some(addr)
+    }
+
+    /// Removes all authorizations matching `f` and returns their keys.
+    fn revoke_where(&self, f: impl Fn(&WhitelistElement) -> bool) -> Vec<IpAddr> {
+        let mut list = self.list.write().expect("Whitelist is poisoned");
+        let mut revoked = Vec::new();
+        list.retain(|k, v| {
+            if f(v) {
+                self.record(Event::Revoke(k));
+                revoked.push(*k);
+                false
+            } else {
+                true
+            }
+        });
+        revoked
     }
 
     fn allow(&self, addr: &IpAddr, headers: &[Header]) {
+        self.allow_until(addr, headers, None);
+    }
+
+    /// Authorizes `addr` until `valid_until`, or according to the expiry policy if it is `None`.
+    fn allow_until(
+        &self,
+        addr: &IpAddr,
+        headers: &[Header],
+        valid_until: Option<DateTime<Utc>>,
+    ) -> (IpAddr, WhitelistElement) {
+        let addr = self.key(addr);
         let mut list = self.list.write().expect("Whitelist is poisoned");
-        list.insert(
-            *addr,
-            WhitelistElement {
-                valid_until: self.new_valid_until(),
-                headers: headers.to_vec(),
-            },
-        );
+        let now = Utc::now();
+        let element = WhitelistElement {
+            authorized_at: now,
+            valid_until: valid_until.unwrap_or_else(|| {
+                self.policy
+                    .read()
+                    .expect("Expiry policy is poisoned")
+                    .valid_until(now)
+            }),
+            headers: headers.to_vec(),
+        };
+        self.record(Event::Allow(&addr, &element));
+        list.insert(addr, element.clone());
+        (addr, element)
     }
 
-    fn new_valid_until(&self) -> DateTime<Utc> {
-        let time = NaiveTime::from_hms_opt(self.hour.into(), self.minute.into(), 0).unwrap();
-        let mut date: DateTime<Utc> = Utc::now();
-        trace!("{:#}",date);
-        date = date.checked_add_days(Days::new(self.days.into())).unwrap();
-        if (date - date.with_time(time).unwrap()) > TimeDelta::zero() {
-            date = date
-                .checked_add_days(Days::new(1))
-                .unwrap()
-                .with_time(time)
-                .unwrap();
-        } else {
-            date = date.with_time(time).unwrap()
+    /// Replaces the expiry policy. If it changed, existing authorizations are shortened to what the
+    /// new policy allows for them, but never extended.
+    fn set_policy(&self, policy: ExpiryPolicy) {
+        let mut list = self.list.write().expect("Whitelist is poisoned");
+        let mut current = self.policy.write().expect("Expiry policy is poisoned");
+        if *current == policy {
+            return;
         }
-        trace!("{:#}",date);
-        return date;
+        let now = Utc::now();
+        let mut shortened = 0;
+        for (k, v) in list.iter_mut() {
+            let valid_until = policy.reapply(v.authorized_at, v.valid_until, now);
+            if valid_until < v.valid_until {
+                v.valid_until = valid_until;
+                self.record(Event::Allow(k, v));
+                shortened += 1;
+            }
+        }
+        info!("Applied the new expiry policy, shortened {shortened} authorizations");
+        *current = policy;
     }
 
-    fn prune(&self) {
+    /// Removes expired authorizations and returns how many there were.
+    fn prune(&self) -> usize {
         let mut list = self.list.write().expect("Whitelist is poisoned");
         let now = Utc::now();
         let zero = TimeDelta::zero();
-        list.retain(|_, v| v.valid_until.signed_duration_since(now) > zero);
+        let before = list.len();
+        list.retain(|k, v| {
+            let keep = v.valid_until.signed_duration_since(now) > zero;
+            if !keep {
+                self.record(Event::Expire(k));
+            }
+            keep
+        });
+        before - list.len()
     }
 }
 
 fn main() -> ExitCode {
     env_logger::init();
-    let settings = Settings::new();
-    if let Err(error) = settings {
-        error!("Failed to parse config: {}", error);
+    let cli = Cli::parse();
+    let settings = match Settings::read(cli.config.as_deref()) {
+        Ok(x) => x,
+        Err(error) => {
+            error!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match cli.command {
+        None | Some(Command::Serve) => serve(SharedSettings::new(cli.config, settings)),
+        Some(Command::Client(command)) => cli::run(&settings, command),
+    }
+}
+
+fn serve(shared: SharedSettings) -> ExitCode {
+    let shared = Arc::new(shared);
+    // Only settings that are not reloaded are read from here
+    let settings = shared.load();
+    let peers = Arc::new(Peers::default());
+    let server = if settings.proxy_protocol {
+        // The HTTP server only receives connections relayed by the PROXY protocol listener
+        let listener = match TcpListener::bind(&settings.listen_address) {
+            Ok(x) => x,
+            Err(error) => {
+                error!("Failed to listen on {}: {error}", settings.listen_address);
+                return ExitCode::FAILURE;
+            }
+        };
+        let server = match Server::http("127.0.0.1:0") {
+            Ok(x) => x,
+            Err(error) => {
+                error!("Failed to start the server for relayed connections: {error}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let target = server.server_addr().to_ip().unwrap();
+        let shared = shared.clone();
+        let peers = peers.clone();
+        thread::spawn(move || proxy_protocol::listen(listener, target, shared, peers));
+        Arc::new(server)
+    } else {
+        match Server::http(&settings.listen_address) {
+            Ok(x) => Arc::new(x),
+            Err(error) => {
+                error!("Failed to listen on {}: {error}", settings.listen_address);
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+    let whitelist = Arc::new(IpWhitelist::build(
+        ExpiryPolicy::new(&settings),
+        settings.ipv6_prefix_length,
+        settings.state_file.as_ref().map(|x| {
+            let snapshot = PathBuf::from(x);
+            let journal = match &settings.journal_file {
+                Some(journal) => PathBuf::from(journal),
+                None => PathBuf::from(format!("{x}.journal")),
+            };
+            Store::new(snapshot, journal)
+        }),
+    ));
+    if let Err(error) = whitelist.restore() {
+        error!("Failed to restore state: {}", error);
         return ExitCode::FAILURE;
     }
-    let settings = settings.unwrap();
-    if let Err(error) = settings.validate() {
-        error!("Failed to validate config: {}", error);
+    HEALTH.state_loaded();
+    if let Err(error) = settings.list_files.update(&settings) {
+        error!("{error}");
         return ExitCode::FAILURE;
     }
-    let settings = Arc::new(settings);
-    let server = Arc::new(Server::http(&settings.listen_address).unwrap());
-    let mut guards = Vec::with_capacity(settings.threads);
-    let whitelist = Arc::new(IpWhitelist::build(
-        settings.minute,
-        settings.hour,
-        settings.days,
-    ));
+    let admin_server = match &settings.admin_listen {
+        Some(address) => match admin::bind(address, settings.admin_socket_mode) {
+            Ok(x) => Some(Arc::new(x)),
+            Err(error) => {
+                error!("Failed to start admin listener on {address}: {}", error);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
 
+    let (shutdown, shutdown_requested) = mpsc::channel();
     {
-        let settings = settings.clone();
-        let server = server.clone();
-        ctrlc::set_handler(move || {
-            info!("Caught Ctrl-C");
-            for _ in 0..settings.threads {
-                server.unblock();
+        let shared = shared.clone();
+        let whitelist = whitelist.clone();
+        let mut signals =
+            Signals::new([SIGHUP, SIGINT, SIGTERM]).expect("Error setting signal handler");
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                match signal {
+                    SIGHUP => {
+                        info!("Caught SIGHUP, reloading config");
+                        if let Err(error) = reload(&shared, &whitelist) {
+                            error!("{error}, keeping the current config");
+                        }
+                    }
+                    // Signals after the first one are ignored, the shutdown is bounded anyway
+                    _ => {
+                        let name = if signal == SIGINT { "SIGINT" } else { "SIGTERM" };
+                        if shutdown.send(()).is_ok() {
+                            info!("Caught {name}, shutting down");
+                        } else {
+                            debug!("Caught {name}, already shutting down");
+                        }
+                    }
+                }
             }
-        })
-        .expect("Error setting Ctrl-C handler");
+        });
+    }
+
+    {
+        let shared = shared.clone();
+        thread::spawn(move || list_file::watch(shared));
     }
 
+    // Every worker sends on `done` when it exits
+    let (done, exited) = mpsc::channel();
+    let mut workers = 0;
     for _ in 0..settings.threads {
-        let settings = settings.clone();
+        let shared = shared.clone();
         let server = server.clone();
         let whitelist = whitelist.clone();
-        let guard = thread::spawn(move || {
-            server_thread(server, &settings, whitelist);
+        let peers = peers.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            server_thread(server, &shared, whitelist, &peers);
+            let _ = done.send(());
         });
+        workers += 1;
+    }
 
-        guards.push(guard);
+    if let Some(admin_server) = admin_server.clone() {
+        let shared = shared.clone();
+        let whitelist = whitelist.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            admin::server_thread(admin_server, &shared, whitelist);
+            let _ = done.send(());
+        });
+        workers += 1;
     }
 
-    let _pruner = {
+    // The pruner runs until `stop_pruner` is dropped
+    let (stop_pruner, pruner_stopped) = mpsc::channel::<()>();
+    let pruner = {
         let whitelist = whitelist.clone();
-        let settings = settings.clone();
-        thread::spawn(move || loop {
-            whitelist.prune();
-            trace!("Pruner run");
-            thread::sleep(Duration::from_secs(settings.prune_interval.into()));
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let _alive = HEALTH.pruner();
+            loop {
+                METRICS.pruned(whitelist.prune());
+                if let Err(error) = whitelist.compact() {
+                    error!("Failed to compact state: {error}");
+                }
+                trace!("Pruner run");
+                let prune_interval = Duration::from_secs(shared.load().prune_interval.into());
+                if let Err(RecvTimeoutError::Disconnected) =
+                    pruner_stopped.recv_timeout(prune_interval)
+                {
+                    break;
+                }
+            }
+            debug!("Pruner exit");
         })
     };
 
-    for guard in guards {
-        let _ = guard.join();
+    let _ = shutdown_requested.recv();
+    drop(shutdown_requested);
+
+    // Workers finish the request they are handling before they notice the unblock
+    for _ in 0..settings.threads {
+        server.unblock();
+    }
+    if let Some(x) = &admin_server {
+        x.unblock();
+    }
+    let shutdown_timeout = Duration::from_secs(shared.load().shutdown_timeout.into());
+    let drained = drain(&exited, workers, shutdown_timeout);
+
+    drop(stop_pruner);
+    let _ = pruner.join();
+    let code = save(&whitelist, drained);
+    if let Some(path) = settings
+        .admin_listen
+        .as_ref()
+        .and_then(|x| x.strip_prefix("unix:"))
+    {
+        let _ = std::fs::remove_file(path);
     }
     info!("Server exit");
-    ExitCode::SUCCESS
+    code
+}
+
+/// Waits up to `timeout` for `workers` workers to report on `exited` that they stopped. Returns
+/// false if some are still handling a request.
+fn drain(exited: &mpsc::Receiver<()>, workers: usize, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    for _ in 0..workers {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if exited.recv_timeout(remaining).is_err() {
+            warn!(
+                "Requests still running after {} seconds, abandoning them",
+                timeout.as_secs()
+            );
+            return false;
+        }
+    }
+    true
+}
+
+/// Saves the whitelist a last time once the pruner stopped. The exit code is a failure if the
+/// workers were not `drained` or the state could not be saved.
+fn save(whitelist: &IpWhitelist, drained: bool) -> ExitCode {
+    if let Err(error) = whitelist.compact() {
+        error!("Failed to save state: {error}");
+        return ExitCode::FAILURE;
+    }
+    if drained {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
 }
 
-fn server_thread(server: Arc<Server>, settings: &Settings, whitelist: Arc<IpWhitelist>) {
+/// Reads the config file again and replaces the current settings if it is valid. Settings that
+/// are only used on startup keep their current value.
+fn reload(shared: &SharedSettings, whitelist: &IpWhitelist) -> Result<(), String> {
+    let current = shared.load();
+    let mut settings = shared.read()?;
+    keep("listen_address", &current.listen_address, &mut settings.listen_address);
+    keep("proxy_protocol", &current.proxy_protocol, &mut settings.proxy_protocol);
+    keep("threads", &current.threads, &mut settings.threads);
+    keep("ipv6_prefix_length", &current.ipv6_prefix_length, &mut settings.ipv6_prefix_length);
+    keep("state_file", &current.state_file, &mut settings.state_file);
+    keep("journal_file", &current.journal_file, &mut settings.journal_file);
+    keep("admin_listen", &current.admin_listen, &mut settings.admin_listen);
+    keep("admin_socket_mode", &current.admin_socket_mode, &mut settings.admin_socket_mode);
+    settings.list_files = current.list_files.clone();
+    if let Err(error) = settings.list_files.update(&settings) {
+        error!("{error}, keeping the current list");
+    }
+    whitelist.set_policy(ExpiryPolicy::new(&settings));
+    shared.store(settings);
+    info!("Reloaded config");
+    Ok(())
+}
+
+/// Restores a setting that can't be changed without a restart.
+fn keep<T: PartialEq + Clone>(name: &str, current: &T, new: &mut T) {
+    if current != new {
+        warn!("Changing {name} requires a restart, keeping the current value");
+        *new = current.clone();
+    }
+}
+
+fn server_thread(
+    server: Arc<Server>,
+    shared: &SharedSettings,
+    whitelist: Arc<IpWhitelist>,
+    peers: &Peers,
+) {
     loop {
         if let Ok(rq) = server.recv() {
+            let settings = &shared.load();
+            // Local processes can connect to the server for relayed connections directly
+            if settings.proxy_protocol && rq.remote_addr().and_then(|x| peers.get(x)).is_none() {
+                warn!("Refused request that was not relayed by the PROXY protocol listener");
+                let _ = rq.respond(Response::from_string("Forbidden").with_status_code(403));
+                continue;
+            }
             trace!(
                 "received request. method: {:?}, url: {:?}, headers: {:?}",
                 rq.method(),
                 rq.url(),
                 rq.headers()
             );
-            match rq.url() {
-                "/allowed" => allowed(settings, &whitelist, rq),
-                "/authorize" => authorize(settings, &whitelist, rq),
-                _ => rq
-                    .respond(Response::from_string("not found").with_status_code(404))
-                    .unwrap(),
-            }
+            let start = Instant::now();
+            let endpoint = match rq.url() {
+                "/allowed" => {
+                    let response = allowed(settings, &whitelist, peers, &rq);
+                    let _ = rq.respond(response);
+                    Endpoint::Allowed
+                }
+                "/authorize" => {
+                    let mut rq = rq;
+                    let response = authorize(settings, &whitelist, peers, &mut rq);
+                    let _ = rq.respond(response);
+                    Endpoint::Authorize
+                }
+                "/healthz" => {
+                    health::healthz(rq);
+                    Endpoint::Other
+                }
+                "/readyz" => {
+                    health::readyz(&whitelist, rq);
+                    Endpoint::Other
+                }
+                "/metrics" if settings.admin_listen.is_none() => {
+                    metrics::handle(&whitelist, rq);
+                    Endpoint::Admin
+                }
+                url if url.starts_with("/admin/") && settings.admin_listen.is_none() => {
+                    admin::handle(shared, &whitelist, rq);
+                    Endpoint::Admin
+                }
+                _ => {
+                    let _ = rq.respond(Response::from_string("not found").with_status_code(404));
+                    Endpoint::Other
+                }
+            };
+            METRICS.request(endpoint, start.elapsed());
         } else {
             debug!("Thread exit");
             break;
@@ -190,50 +596,100 @@ fn server_thread(server: Arc<Server>, settings: &Settings, whitelist: Arc<IpWhit
     }
 }
 
-fn get_ip(rq: &Request) -> IpAddr {
-    for header in rq.headers() {
-        if header.field == HeaderField::from_str("X-Forwarded-For").unwrap() {
-            if let Ok(ip) = IpAddr::from_str(header.value.as_str()) {
-                return ip;
-            } else {
-                warn!("Got request with invalid IP Header: \"{header}\"");
-            }
-        }
+fn allowed(
+    settings: &Settings,
+    whitelist: &IpWhitelist,
+    peers: &Peers,
+    rq: &Request,
+) -> TextResponse {
+    if !proxy_auth::verify(settings, rq) {
+        warn!("Refused request to /allowed without valid proxy authentication");
+        METRICS.allowed(Reason::Unverified);
+        return Response::from_string("Not authenticated").with_status_code(401);
     }
-    rq.remote_addr().unwrap().ip()
-}
+    let addr = forwarded::client_ip(settings, peers, rq);
 
-fn allowed(settings: &Settings, whitelist: &IpWhitelist, rq: Request) {
-    let addr = get_ip(&rq);
+    if settings.is_deny_listed(&addr) {
+        debug!("Denied request from {addr}");
+        METRICS.allowed(Reason::DenyList);
+        return Response::from_string("Access denied").with_status_code(403);
+    }
 
-    if settings.allow_list.contains(&addr){
-        let _ = rq.respond(Response::from_string("Ok"));
-        return;
+    if settings.is_allow_listed(&addr) {
+        METRICS.allowed(Reason::AllowList);
+        return Response::from_string("Ok");
     }
 
-    if let Ok(headers) = whitelist.is_allowed(&addr) {
-        debug!("Allowed request from {addr}");
-        let mut response = Response::from_string("Ok");
-        for header in headers {
-            response.add_header(header);
+    if !in_access_window(settings) {
+        if whitelist
+            .get_ip(&addr)
+            .is_some_and(|x| x.valid_until > Utc::now())
+        {
+            debug!("Request from {addr} outside of the access windows");
+            METRICS.allowed(Reason::AccessWindow);
+            return Response::from_string("Access is not allowed at this time")
+                .with_status_code(403);
         }
-        let _ = rq.respond(response);
-    } else {
         debug!("Forbidden request from {addr}");
-        let _ = rq.respond(
-            Response::from_string("Please (re)authenticate yourself").with_status_code(403),
-        );
+        METRICS.allowed(Reason::Unknown);
+        return Response::from_string("Please (re)authenticate yourself").with_status_code(403);
+    }
+
+    match whitelist.is_allowed(&addr) {
+        Ok(headers) => {
+            debug!("Allowed request from {addr}");
+            METRICS.allowed(Reason::Whitelist);
+            let mut response = Response::from_string("Ok");
+            for header in headers {
+                response.add_header(header);
+            }
+            response
+        }
+        Err(reason) => {
+            debug!("Forbidden request from {addr}");
+            METRICS.allowed(reason);
+            Response::from_string("Please (re)authenticate yourself").with_status_code(403)
+        }
     }
 }
 
-fn authorize(settings: &Settings, whitelist: &IpWhitelist, rq: Request){
-    let addr = get_ip(&rq);
-    let headers: Vec<_> = rq
-        .headers()
-        .iter()
-        .filter(|x| settings.headers.contains(&x.field))
-        .cloned()
-        .collect();
+/// Whether authorized addresses may access the services right now.
+fn in_access_window(settings: &Settings) -> bool {
+    if settings.access_windows.is_empty() {
+        return true;
+    }
+    let now = Utc::now().with_timezone(&settings.timezone).naive_local();
+    settings.access_windows.iter().any(|x| x.contains(now))
+}
+
+fn authorize(
+    settings: &Settings,
+    whitelist: &IpWhitelist,
+    peers: &Peers,
+    rq: &mut Request,
+) -> TextResponse {
+    if !proxy_auth::verify(settings, rq) {
+        warn!("Refused request to /authorize without valid proxy authentication");
+        return Response::from_string("Not authenticated").with_status_code(401);
+    }
+    let addr = forwarded::client_ip(settings, peers, rq);
+    if settings.is_deny_listed(&addr) {
+        warn!("Refused to authorize denied address {addr}");
+        return Response::from_string("Access denied").with_status_code(403);
+    }
+    let headers = if let Some(url) = &settings.forward_auth_url {
+        delegate(settings, url, &addr, rq)
+    } else if let Some(path) = &settings.htpasswd_file {
+        let code = form_value(rq, "code");
+        let client = whitelist.key(&addr);
+        basic_auth(settings, path, &addr, &client, rq, code.as_deref())
+    } else {
+        forwarded_headers(settings, &addr, rq)
+    };
+    let headers = match headers {
+        Ok(x) => x,
+        Err(response) => return response,
+    };
     info!(
         "Authorized {addr} with headers: {}",
         headers
@@ -244,5 +700,451 @@ fn authorize(settings: &Settings, whitelist: &IpWhitelist, rq: Request){
     );
 
     whitelist.allow(&addr, &headers);
-    let _ = rq.respond(Response::from_string("Ok"));
+    METRICS.authorized();
+    Response::from_string("Ok")
+}
+
+type TextResponse = Response<io::Cursor<Vec<u8>>>;
+
+/// The `headers` of a request authenticated by a middleware in front of ip-manager.
+fn forwarded_headers(
+    settings: &Settings,
+    addr: &IpAddr,
+    rq: &Request,
+) -> Result<Vec<Header>, TextResponse> {
+    let missing = missing_headers(settings, rq.headers());
+    if !missing.is_empty() {
+        warn!(
+            "Refused to authorize {addr} without the required headers {}. Is the authentication \
+             middleware in front of /authorize missing?",
+            missing.join(", ")
+        );
+        return Err(Response::from_string("Not authenticated").with_status_code(401));
+    }
+    Ok(rq
+        .headers()
+        .iter()
+        .filter(|x| settings.headers.contains(&x.field))
+        .cloned()
+        .collect())
+}
+
+/// The `required_headers` that are missing or empty in `headers`.
+fn missing_headers(settings: &Settings, headers: &[Header]) -> Vec<String> {
+    settings
+        .required_headers
+        .iter()
+        .filter(|field| {
+            !headers
+                .iter()
+                .any(|x| x.field == **field && !x.value.as_str().trim().is_empty())
+        })
+        .map(|x| x.to_string())
+        .collect()
+}
+
+/// Asks the `forward_auth_url` whether the request is authenticated and returns the headers of
+/// its response to save.
+fn delegate(
+    settings: &Settings,
+    url: &str,
+    addr: &IpAddr,
+    rq: &Request,
+) -> Result<Vec<Header>, TextResponse> {
+    match forward_auth::verify(settings, url, addr, rq) {
+        Ok(Verdict::Allowed(headers)) => {
+            let missing = missing_headers(settings, &headers);
+            if !missing.is_empty() {
+                warn!(
+                    "Refused to authorize {addr}, the response of {url} is missing the required \
+                     headers {}",
+                    missing.join(", ")
+                );
+                return Err(Response::from_string("Not authenticated").with_status_code(401));
+            }
+            Ok(headers)
+        }
+        Ok(Verdict::Refused(response)) => {
+            debug!(
+                "{url} refused to authenticate {addr} with status {}",
+                response.status_code().0
+            );
+            Err(response)
+        }
+        Err(error) => {
+            error!("{error}");
+            Err(Response::from_string("Bad gateway").with_status_code(502))
+        }
+    }
+}
+
+/// The value of `name` in a form posted to `rq`.
+fn form_value(rq: &mut Request, name: &str) -> Option<String> {
+    if *rq.method() != Method::Post {
+        return None;
+    }
+    let mut body = String::new();
+    rq.as_reader().take(4096).read_to_string(&mut body).ok()?;
+    body.split('&')
+        .filter_map(|x| x.split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v.to_string())
+}
+
+/// Authenticates a request with HTTP Basic auth against the `htpasswd_file` at `path`, and with
+/// the TOTP `code` if `totp_file` is set, and returns the user and group headers to save. Failed
+/// logins are counted per `client`, the address or IPv6 prefix of `addr`.
+fn basic_auth(
+    settings: &Settings,
+    path: &str,
+    addr: &IpAddr,
+    client: &IpAddr,
+    rq: &Request,
+    code: Option<&str>,
+) -> Result<Vec<Header>, TextResponse> {
+    let unauthorized = || {
+        Response::from_string("Not authenticated")
+            .with_status_code(401)
+            .with_header(
+                Header::from_bytes("WWW-Authenticate", "Basic realm=\"ip-manager\"").unwrap(),
+            )
+    };
+    let internal_error = || Response::from_string("Internal server error").with_status_code(500);
+
+    let Some((name, password)) = htpasswd::credentials(rq) else {
+        return Err(unauthorized());
+    };
+    let logins = [Login::Client(*client), Login::User(name.clone())];
+    if htpasswd::throttled(&logins) {
+        debug!("Refused login of user \"{name}\" from {addr}, too many failed logins");
+        return Err(Response::from_string("Too many failed logins").with_status_code(429));
+    }
+    let user = match htpasswd::verify(path, &name, &password) {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            warn!("Failed login of user \"{name}\" from {addr}");
+            if htpasswd::failed(&[Login::Client(*client)]) {
+                warn!("Refusing logins from {client} for a minute after too many failures");
+            }
+            return Err(unauthorized());
+        }
+        Err(error) => {
+            error!("{error}");
+            return Err(internal_error());
+        }
+    };
+    if let Some(totp_file) = &settings.totp_file {
+        let secret = match totp::secret(totp_file, &user.name) {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                warn!(
+                    "Refused to authorize {addr} for user \"{}\" without a TOTP secret",
+                    user.name
+                );
+                return Err(Response::from_string("Access denied").with_status_code(403));
+            }
+            Err(error) => {
+                error!("{error}");
+                return Err(internal_error());
+            }
+        };
+        let form = |status, message| {
+            let content_type = Header::from_bytes("Content-Type", "text/html; charset=utf-8");
+            Response::from_string(totp::form(message))
+                .with_status_code(status)
+                .with_header(content_type.unwrap())
+        };
+        let Some(code) = code else {
+            return Err(form(200, None));
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if !totp::verify(&user.name, &secret, code, now, settings.totp_skew) {
+            warn!("Invalid TOTP code of user \"{}\" from {addr}", user.name);
+            if htpasswd::failed(&logins) {
+                warn!(
+                    "Refusing logins of user \"{}\" and from {client} for a minute after too \
+                     many failures",
+                    user.name
+                );
+            }
+            return Err(form(401, Some("Invalid code, please try again")));
+        }
+    }
+    htpasswd::succeeded(&logins);
+    let mut headers = vec![Header::from_bytes(settings.user_header.to_string(), user.name)];
+    if !user.groups.is_empty() {
+        headers.push(Header::from_bytes(
+            settings.groups_header.to_string(),
+            user.groups.join(","),
+        ));
+    }
+    headers.into_iter().collect::<Result<_, _>>().map_err(|_| {
+        error!("User and group names must only contain ascii characters");
+        internal_error()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::{env, fs, process};
+
+    use base64::prelude::*;
+    use tiny_http::TestRequest;
+
+    use super::*;
+
+    fn setup(toml: &str) -> (Settings, IpWhitelist) {
+        let settings = Settings::for_test(toml).unwrap();
+        let whitelist = IpWhitelist::build(
+            ExpiryPolicy::new(&settings),
+            settings.ipv6_prefix_length,
+            None,
+        );
+        (settings, whitelist)
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    /// A request to `path` from the client at `addr`.
+    fn request(path: &str, addr: &str) -> TestRequest {
+        TestRequest::new()
+            .with_path(path)
+            .with_remote_addr(SocketAddr::new(ip(addr), 40000))
+    }
+
+    fn status(response: TextResponse) -> u16 {
+        response.status_code().0
+    }
+
+    #[test]
+    fn deny_list_wins() {
+        let (settings, whitelist) = setup(
+            r#"
+            allow_list = ["192.0.2.0/24"]
+            deny_list = ["192.0.2.1", "198.51.100.1"]
+            "#,
+        );
+        let peers = Peers::default();
+        let allowed = |addr| {
+            let rq = request("/allowed", addr).into();
+            status(allowed(&settings, &whitelist, &peers, &rq))
+        };
+        whitelist.allow(&ip("192.0.2.1"), &[]);
+        whitelist.allow(&ip("198.51.100.1"), &[]);
+        whitelist.allow(&ip("198.51.100.2"), &[]);
+        // Denied although allow listed and authorized
+        assert_eq!(allowed("192.0.2.1"), 403);
+        assert_eq!(allowed("::ffff:192.0.2.1"), 403);
+        // Denied although authorized
+        assert_eq!(allowed("198.51.100.1"), 403);
+        assert_eq!(allowed("192.0.2.2"), 200);
+        assert_eq!(allowed("198.51.100.2"), 200);
+        assert_eq!(allowed("198.51.100.3"), 403);
+    }
+
+    #[test]
+    fn authorize_denied() {
+        let (settings, whitelist) = setup(r#"deny_list = ["192.0.2.0/24"]"#);
+        let peers = Peers::default();
+        let authorize = |addr| {
+            let mut rq = request("/authorize", addr).into();
+            status(authorize(&settings, &whitelist, &peers, &mut rq))
+        };
+        assert_eq!(authorize("192.0.2.1"), 403);
+        assert_eq!(authorize("::ffff:192.0.2.1"), 403);
+        assert_eq!(whitelist.len(), 0);
+        assert_eq!(authorize("198.51.100.1"), 200);
+        assert!(whitelist.get_ip(&ip("198.51.100.1")).is_some());
+    }
+
+    #[test]
+    fn reload_config() {
+        let name = format!("ip-manager-{}-reload.toml", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let config = "headers = []\nclient_ip_headers = []\nexpiry = \"ttl\"\n";
+        std::fs::write(&path, format!("{config}ttl = 3600\nthreads = 2")).unwrap();
+        let file = path.to_str().unwrap().to_string();
+        let settings = Settings::read(Some(&file)).unwrap();
+        let shared = SharedSettings::new(Some(file), settings);
+        let whitelist = IpWhitelist::build(ExpiryPolicy::new(&shared.load()), 64, None);
+        whitelist.allow(&ip("192.0.2.1"), &[]);
+
+        // Settings that need a restart keep their value, the others change
+        let changed = "ttl = 60\nthreads = 8\nlisten_address = \"0.0.0.0:9000\"\nminute = 5";
+        std::fs::write(&path, format!("{config}{changed}")).unwrap();
+        reload(&shared, &whitelist).unwrap();
+        let current = shared.load();
+        assert_eq!(current.threads, 2);
+        assert_eq!(current.listen_address, "127.0.0.1:8080");
+        assert_eq!(current.ttl, 60);
+        assert_eq!(current.minute, 5);
+        let x = whitelist.get_ip(&ip("192.0.2.1")).unwrap();
+        assert_eq!(x.valid_until, x.authorized_at + TimeDelta::seconds(60));
+
+        // An invalid config leaves the current one in place
+        for invalid in ["ttl = 10\nhour = 24", "ttl = 10\nexpiry = \"never\"", "ttl ="] {
+            std::fs::write(&path, format!("{config}{invalid}")).unwrap();
+            assert!(reload(&shared, &whitelist).is_err(), "{invalid}");
+            assert_eq!(shared.load().ttl, 60, "{invalid}");
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn set_policy_only_shortens() {
+        let (settings, whitelist) = setup("expiry = \"ttl\"\nttl = 3600");
+        whitelist.allow(&ip("192.0.2.1"), &[]);
+        let before = whitelist.get_ip(&ip("192.0.2.1")).unwrap();
+        assert_eq!(before.valid_until, before.authorized_at + TimeDelta::hours(1));
+
+        whitelist.set_policy(ExpiryPolicy::Ttl(TimeDelta::hours(2)));
+        let x = whitelist.get_ip(&ip("192.0.2.1")).unwrap();
+        assert_eq!(x.valid_until, before.valid_until);
+        whitelist.set_policy(ExpiryPolicy::Ttl(TimeDelta::minutes(10)));
+        let x = whitelist.get_ip(&ip("192.0.2.1")).unwrap();
+        assert_eq!(x.valid_until, before.authorized_at + TimeDelta::minutes(10));
+        whitelist.set_policy(ExpiryPolicy::new(&settings));
+        let x = whitelist.get_ip(&ip("192.0.2.1")).unwrap();
+        assert_eq!(x.valid_until, before.authorized_at + TimeDelta::minutes(10));
+        // New authorizations use the new policy
+        whitelist.allow(&ip("192.0.2.2"), &[]);
+        let x = whitelist.get_ip(&ip("192.0.2.2")).unwrap();
+        assert_eq!(x.valid_until, x.authorized_at + TimeDelta::hours(1));
+    }
+
+    #[test]
+    fn drain_workers() {
+        let (done, exited) = mpsc::channel();
+        for _ in 0..2 {
+            done.send(()).unwrap();
+        }
+        assert!(drain(&exited, 2, Duration::ZERO));
+        // A worker finishing its request within the timeout
+        let late = done.clone();
+        let worker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            late.send(()).unwrap();
+        });
+        assert!(drain(&exited, 1, Duration::from_secs(5)));
+        worker.join().unwrap();
+        // One of two workers is stuck
+        done.send(()).unwrap();
+        let start = Instant::now();
+        assert!(!drain(&exited, 2, Duration::from_millis(100)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn save_on_shutdown() {
+        let base = std::env::temp_dir().join(format!("ip-manager-{}-save", std::process::id()));
+        let (snapshot, journal) = (base.with_extension("json"), base.with_extension("journal"));
+        let store = Store::new(snapshot.clone(), journal.clone());
+        let whitelist = IpWhitelist::build(ExpiryPolicy::Ttl(TimeDelta::hours(1)), 64, Some(store));
+        whitelist.restore().unwrap();
+        whitelist.allow(&ip("192.0.2.1"), &[]);
+        assert!(std::fs::metadata(&journal).unwrap().len() > 0);
+
+        assert_eq!(save(&whitelist, true), ExitCode::SUCCESS);
+        assert_eq!(std::fs::metadata(&journal).unwrap().len(), 0);
+        let saved = Store::new(snapshot.clone(), journal.clone()).load().unwrap();
+        assert!(saved.contains_key(&ip("192.0.2.1")));
+        // Abandoned requests are reported in the exit code, the state is still saved
+        whitelist.allow(&ip("192.0.2.2"), &[]);
+        assert_eq!(save(&whitelist, false), ExitCode::FAILURE);
+        let saved = Store::new(snapshot.clone(), journal.clone()).load().unwrap();
+        assert_eq!(saved.len(), 2);
+        std::fs::remove_file(&snapshot).unwrap();
+        std::fs::remove_file(&journal).unwrap();
+
+        // A state that can't be saved
+        let missing = base.join("missing");
+        let store = Store::new(missing.join("state.json"), missing.join("journal"));
+        let whitelist = IpWhitelist::build(ExpiryPolicy::Ttl(TimeDelta::hours(1)), 64, Some(store));
+        assert_eq!(save(&whitelist, true), ExitCode::FAILURE);
+    }
+
+    #[test]
+    fn required_headers() {
+        let (settings, whitelist) = setup(r#"required_headers = ["Remote-User", "Remote-Email"]"#);
+        let headers = |x: &[(&str, &str)]| -> Vec<Header> {
+            x.iter()
+                .map(|(k, v)| Header::from_bytes(*k, *v).unwrap())
+                .collect()
+        };
+        let missing = |x: &[(&str, &str)]| missing_headers(&settings, &headers(x));
+        assert_eq!(missing(&[]), ["Remote-User", "Remote-Email"]);
+        assert_eq!(
+            missing(&[("remote-user", "alice"), ("Remote-Email", " \t ")]),
+            ["Remote-Email"]
+        );
+        assert_eq!(
+            missing(&[("Remote-User", ""), ("Remote-Email", "alice@example.com")]),
+            ["Remote-User"]
+        );
+        // Any non-empty occurrence counts
+        let present = [
+            ("Remote-User", ""),
+            ("Remote-User", "alice"),
+            ("Remote-Email", "alice@example.com"),
+        ];
+        assert!(missing(&present).is_empty());
+
+        let peers = Peers::default();
+        let mut rq = request("/authorize", "192.0.2.1")
+            .with_header(Header::from_bytes("Remote-User", "alice").unwrap())
+            .into();
+        assert_eq!(status(authorize(&settings, &whitelist, &peers, &mut rq)), 401);
+        assert_eq!(whitelist.len(), 0);
+        let mut rq = request("/authorize", "192.0.2.1")
+            .with_header(Header::from_bytes("Remote-User", "alice").unwrap())
+            .with_header(Header::from_bytes("Remote-Email", "alice@example.com").unwrap())
+            .into();
+        assert_eq!(status(authorize(&settings, &whitelist, &peers, &mut rq)), 200);
+        assert_eq!(whitelist.len(), 1);
+    }
+
+    #[test]
+    fn totp_failures() {
+        let base = env::temp_dir().join(format!("ip-manager-{}-totp-failures", process::id()));
+        let htpasswd = base.with_extension("htpasswd");
+        let totp = base.with_extension("totp");
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        fs::write(&htpasswd, format!("alice:{hash}\nbob:{hash}\n")).unwrap();
+        fs::write(&totp, "alice:JBSWY3DPEHPK3PXP\nbob:JBSWY3DPEHPK3PXP\n").unwrap();
+        let (settings, _) = setup(&format!(
+            "htpasswd_file = {:?}\ntotp_file = {:?}",
+            htpasswd.to_str().unwrap(),
+            totp.to_str().unwrap()
+        ));
+        let login = |user: &str, addr: &str, code| {
+            let credentials = BASE64_STANDARD.encode(format!("{user}:secret"));
+            let authorization = format!("Basic {credentials}");
+            let rq = request("/authorize", addr)
+                .with_header(Header::from_bytes("Authorization", authorization).unwrap())
+                .into();
+            let path = settings.htpasswd_file.as_deref().unwrap();
+            match basic_auth(&settings, path, &ip(addr), &ip(addr), &rq, code) {
+                Ok(_) => 200,
+                Err(response) => status(response),
+            }
+        };
+
+        assert_eq!(login("alice", "192.0.2.24", None), 200);
+        for _ in 0..5 {
+            assert_eq!(login("alice", "192.0.2.24", Some("12345x")), 401);
+        }
+        // Throttled for the client and for the user, also with the right password
+        assert_eq!(login("alice", "192.0.2.24", Some("12345x")), 429);
+        assert_eq!(login("alice", "192.0.2.25", None), 429);
+        assert_eq!(login("bob", "192.0.2.24", None), 429);
+        assert_eq!(login("bob", "192.0.2.25", None), 200);
+        fs::remove_file(&htpasswd).unwrap();
+        fs::remove_file(&totp).unwrap();
+    }
 }
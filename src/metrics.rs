@@ -0,0 +1,316 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tiny_http::{Header, Request, Response};
+
+use crate::IpWhitelist;
+
+/// Why `/allowed` accepted or rejected a request.
+#[derive(Clone, Copy)]
+pub enum Reason {
+    AllowList,
+    Whitelist,
+    DenyList,
+    Unverified,
+    AccessWindow,
+    Expired,
+    Unknown,
+}
+
+impl Reason {
+    const ALL: [Self; 7] = [
+        Self::AllowList,
+        Self::Whitelist,
+        Self::DenyList,
+        Self::Unverified,
+        Self::AccessWindow,
+        Self::Expired,
+        Self::Unknown,
+    ];
+
+    fn labels(self) -> &'static str {
+        match self {
+            Self::AllowList => r#"result="allow",reason="allow_list""#,
+            Self::Whitelist => r#"result="allow",reason="whitelist""#,
+            Self::DenyList => r#"result="deny",reason="deny_list""#,
+            Self::Unverified => r#"result="deny",reason="unverified""#,
+            Self::AccessWindow => r#"result="deny",reason="access_window""#,
+            Self::Expired => r#"result="deny",reason="expired""#,
+            Self::Unknown => r#"result="deny",reason="unknown""#,
+        }
+    }
+}
+
+/// The endpoint a request was routed to.
+#[derive(Clone, Copy)]
+pub enum Endpoint {
+    Allowed,
+    Authorize,
+    Admin,
+    Other,
+}
+
+impl Endpoint {
+    const ALL: [Self; 4] = [Self::Allowed, Self::Authorize, Self::Admin, Self::Other];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Allowed => "allowed",
+            Self::Authorize => "authorize",
+            Self::Admin => "admin",
+            Self::Other => "other",
+        }
+    }
+}
+
+const BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|x| seconds <= *x) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bucket, count) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                r#"{name}_bucket{{{labels},le="{bucket}"}} {cumulative}"#
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, r#"{name}_bucket{{{labels},le="+Inf"}} {count}"#);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+        let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+/// Counters exported on `/metrics`.
+pub struct Metrics {
+    allowed: [AtomicU64; Reason::ALL.len()],
+    authorized: AtomicU64,
+    prune_runs: AtomicU64,
+    pruned: AtomicU64,
+    invalid_forwarded_headers: AtomicU64,
+    durations: [Histogram; Endpoint::ALL.len()],
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            allowed: [const { AtomicU64::new(0) }; Reason::ALL.len()],
+            authorized: AtomicU64::new(0),
+            prune_runs: AtomicU64::new(0),
+            pruned: AtomicU64::new(0),
+            invalid_forwarded_headers: AtomicU64::new(0),
+            durations: [const { Histogram::new() }; Endpoint::ALL.len()],
+        }
+    }
+
+    pub fn allowed(&self, reason: Reason) {
+        self.allowed[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn authorized(&self) {
+        self.authorized.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn pruned(&self, count: usize) {
+        self.prune_runs.fetch_add(1, Ordering::Relaxed);
+        self.pruned.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn invalid_forwarded_header(&self) {
+        self.invalid_forwarded_headers
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn request(&self, endpoint: Endpoint, duration: Duration) {
+        self.durations[endpoint as usize].observe(duration);
+    }
+
+    fn render(&self, whitelist_size: usize) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        };
+
+        let _ = writeln!(
+            out,
+            "# HELP ip_manager_allowed_total Decisions on /allowed requests."
+        );
+        let _ = writeln!(out, "# TYPE ip_manager_allowed_total counter");
+        for reason in Reason::ALL {
+            let _ = writeln!(
+                out,
+                "ip_manager_allowed_total{{{}}} {}",
+                reason.labels(),
+                self.allowed[reason as usize].load(Ordering::Relaxed)
+            );
+        }
+        counter(
+            &mut out,
+            "ip_manager_authorize_total",
+            "Addresses authorized through /authorize.",
+            &self.authorized,
+        );
+        counter(
+            &mut out,
+            "ip_manager_prune_runs_total",
+            "Runs of the pruner.",
+            &self.prune_runs,
+        );
+        counter(
+            &mut out,
+            "ip_manager_pruned_total",
+            "Expired authorizations removed by the pruner.",
+            &self.pruned,
+        );
+        counter(
+            &mut out,
+            "ip_manager_invalid_forwarded_headers_total",
+            "Requests with an invalid client address header.",
+            &self.invalid_forwarded_headers,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP ip_manager_whitelist_size Authorizations in the whitelist."
+        );
+        let _ = writeln!(out, "# TYPE ip_manager_whitelist_size gauge");
+        let _ = writeln!(out, "ip_manager_whitelist_size {whitelist_size}");
+
+        let name = "ip_manager_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} Time to handle a request.");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for endpoint in Endpoint::ALL {
+            let labels = format!(r#"endpoint="{}""#, endpoint.name());
+            self.durations[endpoint as usize].render(&mut out, name, &labels);
+        }
+        out
+    }
+}
+
+/// Responds to `/metrics` in the Prometheus text format.
+pub fn handle(whitelist: &IpWhitelist, rq: Request) {
+    let body = METRICS.render(whitelist.len());
+    let _ = rq.respond(Response::from_string(body).with_header(
+        Header::from_bytes("Content-Type", "text/plain; version=0.0.4; charset=utf-8").unwrap(),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value of the sample `name` in `out`.
+    fn sample(out: &str, name: &str) -> f64 {
+        out.lines()
+            .find_map(|x| x.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("{name} is missing"))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn allowed_labels() {
+        let metrics = Metrics::new();
+        metrics.allowed(Reason::DenyList);
+        metrics.allowed(Reason::DenyList);
+        metrics.allowed(Reason::Whitelist);
+        let out = metrics.render(3);
+        let allowed: Vec<_> = out
+            .lines()
+            .filter(|x| x.starts_with("ip_manager_allowed_total{"))
+            .collect();
+        assert_eq!(
+            allowed,
+            [
+                r#"ip_manager_allowed_total{result="allow",reason="allow_list"} 0"#,
+                r#"ip_manager_allowed_total{result="allow",reason="whitelist"} 1"#,
+                r#"ip_manager_allowed_total{result="deny",reason="deny_list"} 2"#,
+                r#"ip_manager_allowed_total{result="deny",reason="unverified"} 0"#,
+                r#"ip_manager_allowed_total{result="deny",reason="access_window"} 0"#,
+                r#"ip_manager_allowed_total{result="deny",reason="expired"} 0"#,
+                r#"ip_manager_allowed_total{result="deny",reason="unknown"} 0"#,
+            ]
+        );
+        assert_eq!(sample(&out, "ip_manager_whitelist_size"), 3.0);
+    }
+
+    #[test]
+    fn histogram() {
+        let metrics = Metrics::new();
+        for millis in [0.3, 2.0, 2.0, 300.0, 10_000.0] {
+            metrics.request(Endpoint::Allowed, Duration::from_secs_f64(millis / 1000.0));
+        }
+        metrics.request(Endpoint::Admin, Duration::from_millis(1));
+        let out = metrics.render(0);
+
+        let name = "ip_manager_request_duration_seconds";
+        let buckets: Vec<f64> = BUCKETS
+            .iter()
+            .map(|le| {
+                sample(
+                    &out,
+                    &format!(r#"{name}_bucket{{endpoint="allowed",le="{le}"}}"#),
+                )
+            })
+            .collect();
+        assert_eq!(
+            buckets,
+            [1.0, 1.0, 3.0, 3.0, 3.0, 3.0, 3.0, 3.0, 3.0, 4.0, 4.0, 4.0]
+        );
+        let count = sample(&out, &format!(r#"{name}_count{{endpoint="allowed"}}"#));
+        assert_eq!(count, 5.0);
+        let inf = sample(
+            &out,
+            &format!(r#"{name}_bucket{{endpoint="allowed",le="+Inf"}}"#),
+        );
+        assert_eq!(inf, count);
+        let sum = sample(&out, &format!(r#"{name}_sum{{endpoint="allowed"}}"#));
+        assert!((sum - 10.3043).abs() < 1e-6, "{sum}");
+        // Every endpoint is rendered, also without requests
+        for endpoint in Endpoint::ALL {
+            let labels = format!(r#"endpoint="{}""#, endpoint.name());
+            let inf = sample(&out, &format!(r#"{name}_bucket{{{labels},le="+Inf"}}"#));
+            assert_eq!(inf, sample(&out, &format!("{name}_count{{{labels}}}")));
+        }
+        assert_eq!(
+            sample(&out, &format!(r#"{name}_count{{endpoint="admin"}}"#)),
+            1.0
+        );
+        assert_eq!(
+            sample(&out, &format!(r#"{name}_count{{endpoint="other"}}"#)),
+            0.0
+        );
+    }
+}
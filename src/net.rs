@@ -0,0 +1,277 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// An IP network in CIDR notation. A plain address is treated as a network with the full prefix
+/// length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr.trim()).map_err(|e| format!("\"{s}\": {e}"))?;
+        let max = max_prefix(&addr);
+        let prefix = match prefix {
+            Some(x) => x
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|x| *x <= max)
+                .ok_or_else(|| format!("\"{s}\": prefix length must be between 0 and {max}"))?,
+            None => max,
+        };
+        if prefix_bits(&addr, prefix) != bits(&addr) {
+            return Err(format!("\"{s}\": address has bits set after the prefix"));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::from_str(&value)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Clears all bits of `addr` after the first `prefix` bits.
+pub fn truncate(addr: &IpAddr, prefix: u8) -> IpAddr {
+    let bits = prefix_bits(addr, prefix.min(max_prefix(addr)));
+    match addr {
+        IpAddr::V4(_) => IpAddr::V4(((bits >> 96) as u32).into()),
+        IpAddr::V6(_) => IpAddr::V6(bits.into()),
+    }
+}
+
+fn max_prefix(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// The address as a left aligned bit string.
+fn bits(addr: &IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(x) => u128::from(u32::from(*x)) << 96,
+        IpAddr::V6(x) => u128::from(*x),
+    }
+}
+
+fn prefix_bits(addr: &IpAddr, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        bits(addr) & (u128::MAX << (128 - u32::from(prefix)))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: [Option<Box<Node>>; 2],
+    terminal: bool,
+}
+
+impl Node {
+    fn insert(&mut self, bits: u128, prefix: u8) {
+        let mut node = self;
+        for i in 0..prefix {
+            if node.terminal {
+                return;
+            }
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            node = node.children[bit].get_or_insert_with(Default::default);
+        }
+        node.terminal = true;
+        node.children = Default::default();
+    }
+
+    fn contains(&self, bits: u128, len: u8) -> bool {
+        let mut node = self;
+        for i in 0..len {
+            if node.terminal {
+                return true;
+            }
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            match &node.children[bit] {
+                Some(x) => node = x,
+                None => return false,
+            }
+        }
+        node.terminal
+    }
+}
+
+/// A set of networks stored in a binary prefix trie, so that lookups take at most one step per
+/// address bit regardless of the number of networks. IPv4-mapped IPv6 addresses and networks are
+/// treated as IPv4.
+#[derive(Debug, Default)]
+pub struct PrefixSet {
+    v4: Node,
+    v6: Node,
+}
+
+impl PrefixSet {
+    pub fn insert(&mut self, net: IpNet) {
+        match (net.addr, net.addr.to_canonical()) {
+            (IpAddr::V6(_), addr @ IpAddr::V4(_)) if net.prefix >= 96 => {
+                self.v4.insert(bits(&addr), net.prefix - 96)
+            }
+            (addr @ IpAddr::V4(_), _) => self.v4.insert(bits(&addr), net.prefix),
+            (addr @ IpAddr::V6(_), _) => self.v6.insert(bits(&addr), net.prefix),
+        }
+    }
+
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        match addr {
+            IpAddr::V4(_) => self.v4.contains(bits(&addr), 32),
+            IpAddr::V6(_) => self.v6.contains(bits(&addr), 128),
+        }
+    }
+}
+
+impl FromIterator<IpNet> for PrefixSet {
+    fn from_iter<T: IntoIterator<Item = IpNet>>(iter: T) -> Self {
+        let mut set = Self::default();
+        for net in iter {
+            set.insert(net);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(nets: &[&str]) -> PrefixSet {
+        nets.iter().map(|x| x.parse::<IpNet>().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "10.0.0.0/8".parse::<IpNet>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+        assert_eq!(
+            " 10.1.2.3 ".parse::<IpNet>().unwrap().to_string(),
+            "10.1.2.3/32"
+        );
+        assert_eq!(
+            "2001:db8::/32".parse::<IpNet>().unwrap().to_string(),
+            "2001:db8::/32"
+        );
+        assert_eq!("::1".parse::<IpNet>().unwrap().to_string(), "::1/128");
+        assert_eq!(
+            "0.0.0.0/0".parse::<IpNet>().unwrap().to_string(),
+            "0.0.0.0/0"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        for x in [
+            "10.0.0.1/8",
+            "2001:db8::1/64",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/-1",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "example.com",
+        ] {
+            assert!(x.parse::<IpNet>().is_err(), "{x}");
+        }
+    }
+
+    #[test]
+    fn contains() {
+        // Shorter prefixes cover longer ones in either order
+        let s = set(&["10.1.2.3", "10.0.0.0/8", "192.168.1.0/24", "192.168.0.0/16"]);
+        assert!(s.contains(&ip("10.1.2.3")));
+        assert!(s.contains(&ip("10.200.0.1")));
+        assert!(s.contains(&ip("192.168.200.1")));
+        assert!(!s.contains(&ip("11.0.0.1")));
+        assert!(!s.contains(&ip("192.169.0.1")));
+
+        let s = set(&["10.1.2.3", "172.16.0.0/12"]);
+        assert!(s.contains(&ip("10.1.2.3")));
+        assert!(!s.contains(&ip("10.1.2.2")));
+        assert!(!s.contains(&ip("10.1.2.4")));
+        assert!(s.contains(&ip("172.31.255.255")));
+        assert!(!s.contains(&ip("172.32.0.0")));
+        assert!(!PrefixSet::default().contains(&ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn contains_full_and_empty_prefix() {
+        let s = set(&["0.0.0.0/0", "2001:db8::1/128"]);
+        assert!(s.contains(&ip("1.2.3.4")));
+        assert!(s.contains(&ip("255.255.255.255")));
+        assert!(s.contains(&ip("2001:db8::1")));
+        assert!(!s.contains(&ip("2001:db8::2")));
+
+        let s = set(&["::/0", "192.0.2.1/32"]);
+        assert!(s.contains(&ip("2001:db8::1")));
+        assert!(s.contains(&ip("192.0.2.1")));
+        assert!(!s.contains(&ip("192.0.2.2")));
+    }
+
+    #[test]
+    fn contains_mixed_families() {
+        // 10.0.0.0 and 0a00:: have the same leading bits
+        let s = set(&["10.0.0.0/8", "2001:db8::/32"]);
+        assert!(!s.contains(&ip("a00::1")));
+        assert!(!s.contains(&ip("32.1.13.184")));
+        assert!(s.contains(&ip("2001:db8:ffff::1")));
+        assert!(!s.contains(&ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn contains_ipv4_mapped() {
+        let s = set(&["192.0.2.0/24"]);
+        assert!(s.contains(&ip("::ffff:192.0.2.5")));
+        assert!(!s.contains(&ip("::ffff:192.0.3.5")));
+
+        let s = set(&["::ffff:192.0.2.0/120"]);
+        assert!(s.contains(&ip("192.0.2.5")));
+        assert!(s.contains(&ip("::ffff:192.0.2.5")));
+        assert!(!s.contains(&ip("192.0.3.5")));
+    }
+
+    #[test]
+    fn truncate_addresses() {
+        assert_eq!(
+            truncate(&ip("2001:db8:1:2:3:4:5:6"), 64),
+            ip("2001:db8:1:2::")
+        );
+        assert_eq!(truncate(&ip("2001:db8::1"), 128), ip("2001:db8::1"));
+        assert_eq!(truncate(&ip("192.0.2.200"), 24), ip("192.0.2.0"));
+        assert_eq!(truncate(&ip("192.0.2.200"), 64), ip("192.0.2.200"));
+    }
+}
@@ -0,0 +1,235 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tiny_http::{HeaderField, Request};
+
+use crate::admin::constant_time_eq;
+use crate::forwarded::ClientIpHeader;
+use crate::settings::Settings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyAuth {
+    /// The header contains one of the secrets
+    Secret,
+    /// The header contains a timestamp and an HMAC-SHA256 signature of the request
+    Hmac,
+}
+
+/// Whether `rq` was sent by the proxy, proven by the `proxy_auth_header`. Always true without
+/// `proxy_secrets`.
+pub fn verify(settings: &Settings, rq: &Request) -> bool {
+    if settings.proxy_secrets.is_empty() {
+        return true;
+    }
+    let Some(value) = header(rq, &settings.proxy_auth_header) else {
+        return false;
+    };
+    match settings.proxy_auth {
+        ProxyAuth::Secret => settings
+            .proxy_secrets
+            .iter()
+            .any(|x| constant_time_eq(value.trim().as_bytes(), x.as_bytes())),
+        ProxyAuth::Hmac => verify_signature(settings, rq, value),
+    }
+}
+
+/// Checks a signature of the form `t=<unix time>,s=<hex encoded HMAC-SHA256>`.
+fn verify_signature(settings: &Settings, rq: &Request, value: &str) -> bool {
+    let mut timestamp = None;
+    let mut signature = None;
+    for part in value.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", x)) => timestamp = x.parse::<i64>().ok(),
+            Some(("s", x)) => signature = hex::decode(x).ok(),
+            _ => (),
+        }
+    }
+    let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+        return false;
+    };
+    if Utc::now().timestamp().abs_diff(timestamp) > settings.proxy_auth_max_age.into() {
+        return false;
+    }
+    let Some(message) = signed_message(settings, rq, timestamp) else {
+        return false;
+    };
+    settings.proxy_secrets.iter().any(|secret| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(message.as_bytes());
+        mac.verify_slice(&signature).is_ok()
+    })
+}
+
+/// The timestamp, method and URL of the request followed by the values of the
+/// `client_ip_headers` and the `headers`, one per line. Missing headers are empty lines. `None` if
+/// one of these headers occurs more than once, as a repeated header could be added after signing.
+fn signed_message(settings: &Settings, rq: &Request, timestamp: i64) -> Option<String> {
+    let mut message = format!("{timestamp}\n{}\n{}", rq.method(), rq.url());
+    let fields = settings
+        .client_ip_headers
+        .iter()
+        .map(ClientIpHeader::field)
+        .chain(settings.headers.iter().cloned());
+    for field in fields {
+        let mut values = rq.headers().iter().filter(|x| x.field == field);
+        let value = values.next().map(|x| x.value.as_str());
+        if values.next().is_some() {
+            return None;
+        }
+        message.push('\n');
+        message.push_str(value.unwrap_or_default());
+    }
+    Some(message)
+}
+
+fn header<'a>(rq: &'a Request, field: &HeaderField) -> Option<&'a str> {
+    rq.headers()
+        .iter()
+        .find(|x| x.field == *field)
+        .map(|x| x.value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use tiny_http::{Header, TestRequest};
+
+    use super::*;
+
+    const HMAC: &str = "proxy_secrets = [\"s3cret\"]\nproxy_auth = \"hmac\"";
+    /// HMAC-SHA256 with the secret `s3cret` of the request in `known_signature`
+    const SIGNATURE: &str = "28fe0d9d53a256ce800aab56f95c784df07c22fa3b3b21854a1cb170645b7bd3";
+
+    fn settings(toml: &str) -> Settings {
+        Settings::for_test(&format!(
+            "headers = [\"Remote-User\"]\ntrusted_proxies = [\"127.0.0.1\"]\n{toml}"
+        ))
+        .unwrap()
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        headers
+            .iter()
+            .fold(TestRequest::new().with_path("/allowed"), |rq, (k, v)| {
+                rq.with_header(Header::from_str(&format!("{k}: {v}")).unwrap())
+            })
+            .into()
+    }
+
+    fn sign(secret: &str, message: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(message.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn shared_secret() {
+        let settings = settings("proxy_secrets = [\"old\", \"new\"]");
+        assert!(verify(&settings, &request(&[("X-Proxy-Auth", "new")])));
+        assert!(verify(&settings, &request(&[("X-Proxy-Auth", " old ")])));
+        assert!(!verify(&settings, &request(&[("X-Proxy-Auth", "ne")])));
+        assert!(!verify(&settings, &request(&[("X-Proxy-Auth", "")])));
+        assert!(!verify(&settings, &request(&[("X-Other", "new")])));
+        assert!(!verify(&settings, &request(&[])));
+        // Without secrets every request is accepted
+        assert!(verify(&self::settings(""), &request(&[])));
+    }
+
+    #[test]
+    fn known_signature() {
+        let settings = settings(&format!("{HMAC}\nproxy_auth_max_age = 4000000000"));
+        let headers = [("X-Forwarded-For", "192.0.2.1"), ("Remote-User", "alice")];
+        let rq = request(&headers);
+        assert_eq!(
+            signed_message(&settings, &rq, 1700000000).unwrap(),
+            "1700000000\nGET\n/allowed\n192.0.2.1\nalice"
+        );
+        assert!(verify_signature(
+            &settings,
+            &rq,
+            &format!("t=1700000000,s={SIGNATURE}")
+        ));
+        assert!(verify_signature(
+            &settings,
+            &rq,
+            &format!("s={SIGNATURE}, t=1700000000")
+        ));
+        assert!(!verify_signature(
+            &settings,
+            &rq,
+            &format!("t=1700000001,s={SIGNATURE}")
+        ));
+        assert!(!verify_signature(&settings, &rq, "t=1700000000,s=28fe"));
+        assert!(!verify_signature(&settings, &rq, "t=1700000000,s=not hex"));
+        assert!(!verify_signature(&settings, &rq, &format!("s={SIGNATURE}")));
+        // Any change to a signed header breaks the signature
+        let rq = request(&[("X-Forwarded-For", "192.0.2.2"), ("Remote-User", "alice")]);
+        assert!(!verify_signature(
+            &settings,
+            &rq,
+            &format!("t=1700000000,s={SIGNATURE}")
+        ));
+        let rq = request(&headers[..1]);
+        assert!(!verify_signature(
+            &settings,
+            &rq,
+            &format!("t=1700000000,s={SIGNATURE}")
+        ));
+    }
+
+    #[test]
+    fn repeated_signed_header() {
+        let settings = settings(&format!("{HMAC}\nproxy_auth_max_age = 4000000000"));
+        let rq = request(&[
+            ("X-Forwarded-For", "192.0.2.1"),
+            ("Remote-User", "alice"),
+            ("X-Forwarded-For", "198.51.100.1"),
+        ]);
+        assert_eq!(signed_message(&settings, &rq, 1700000000), None);
+        assert!(!verify_signature(
+            &settings,
+            &rq,
+            &format!("t=1700000000,s={SIGNATURE}")
+        ));
+        // Repeated headers that are not signed don't matter
+        let rq = request(&[
+            ("X-Forwarded-For", "192.0.2.1"),
+            ("Remote-User", "alice"),
+            ("Accept", "text/html"),
+            ("Accept", "*/*"),
+        ]);
+        assert!(verify_signature(
+            &settings,
+            &rq,
+            &format!("t=1700000000,s={SIGNATURE}")
+        ));
+    }
+
+    #[test]
+    fn timestamp_window() {
+        let settings = settings(HMAC);
+        let rq = request(&[]);
+        let now = Utc::now().timestamp();
+        let signed = |t: i64| {
+            let message = signed_message(&settings, &rq, t).unwrap();
+            format!("t={t},s={}", sign("s3cret", &message))
+        };
+        assert!(verify(
+            &settings,
+            &request(&[("X-Proxy-Auth", &signed(now))])
+        ));
+        for t in [now - 290, now + 290] {
+            assert!(verify_signature(&settings, &rq, &signed(t)), "{}", t - now);
+        }
+        for t in [now - 310, now + 310, 0, i64::MIN, i64::MAX] {
+            assert!(!verify_signature(&settings, &rq, &signed(t)), "{t}");
+        }
+        // Signed with a secret that is not configured
+        let message = signed_message(&settings, &rq, now).unwrap();
+        let value = format!("t={now},s={}", sign("other", &message));
+        assert!(!verify_signature(&settings, &rq, &value));
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, trace, warn};
+
+use crate::settings::SharedSettings;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LENGTH: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Connections are closed after this long without data from the proxy.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Maximum number of connections relayed at the same time.
+const MAX_CONNECTIONS: usize = 512;
+
+/// Client addresses received in PROXY protocol headers, keyed by the address of the relayed
+/// connection as seen by the HTTP server.
+#[derive(Default)]
+pub struct Peers {
+    list: RwLock<HashMap<SocketAddr, IpAddr>>,
+}
+
+impl Peers {
+    /// The address of the client behind the connection from `addr`, if it was relayed. Any other
+    /// connection to the HTTP server comes from a local process bypassing the PROXY protocol
+    /// listener.
+    pub fn get(&self, addr: &SocketAddr) -> Option<IpAddr> {
+        self.list
+            .read()
+            .expect("Peer list is poisoned")
+            .get(addr)
+            .copied()
+    }
+
+    fn insert(&self, addr: SocketAddr, client: IpAddr) {
+        self.list
+            .write()
+            .expect("Peer list is poisoned")
+            .insert(addr, client);
+    }
+
+    fn remove(&self, addr: &SocketAddr) {
+        self.list
+            .write()
+            .expect("Peer list is poisoned")
+            .remove(addr);
+    }
+}
+
+/// Decrements the number of open connections when dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts connections on `listener`, strips the PROXY protocol header and relays the rest of
+/// the connection to the HTTP server at `target`. Headers are only accepted from
+/// `trusted_proxies`; other connections are closed, as are connections beyond
+/// `MAX_CONNECTIONS`.
+pub fn listen(
+    listener: TcpListener,
+    target: SocketAddr,
+    shared: Arc<SharedSettings>,
+    peers: Arc<Peers>,
+) {
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let (stream, peer) = match stream.and_then(|x| x.peer_addr().map(|peer| (x, peer))) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to accept connection: {e}");
+                continue;
+            }
+        };
+        if !shared.load().trusted_proxies.contains(&peer.ip()) {
+            warn!(
+                "Refused PROXY protocol connection from untrusted address {}",
+                peer.ip()
+            );
+            continue;
+        }
+        if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            open.fetch_sub(1, Ordering::SeqCst);
+            warn!("Refused PROXY protocol connection from {peer}, too many open connections");
+            continue;
+        }
+        let slot = Slot(open.clone());
+        let peers = peers.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = relay(stream, target, &peers) {
+                debug!("Closed PROXY protocol connection from {peer}: {e}");
+            }
+        });
+    }
+}
+
+fn relay(mut stream: TcpStream, target: SocketAddr, peers: &Peers) -> io::Result<()> {
+    let peer = stream.peer_addr()?.ip();
+    stream.set_read_timeout(Some(HEADER_TIMEOUT))?;
+    let client = read_header(&mut stream)?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let client = client.unwrap_or(peer);
+    trace!("PROXY protocol connection from {peer} for {client}");
+
+    let mut upstream = TcpStream::connect(target)?;
+    let local = upstream.local_addr()?;
+    peers.insert(local, client);
+
+    let mut upstream_read = upstream.try_clone()?;
+    let mut stream_write = stream.try_clone()?;
+    let back = thread::spawn(move || {
+        let _ = io::copy(&mut upstream_read, &mut stream_write);
+        let _ = stream_write.shutdown(Shutdown::Write);
+    });
+    let result = io::copy(&mut stream, &mut upstream);
+    let _ = upstream.shutdown(Shutdown::Write);
+    let _ = back.join();
+    peers.remove(&local);
+    result.map(|_| ())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads a v1 or v2 PROXY protocol header and returns the source address it contains, if any.
+/// Nothing past the end of the header is consumed.
+fn read_header(stream: &mut impl Read) -> io::Result<Option<IpAddr>> {
+    let mut buf = vec![0; 16];
+    stream.read_exact(&mut buf[..5])?;
+    if &buf[..5] == b"PROXY" {
+        buf.truncate(5);
+        let mut byte = [0];
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= V1_MAX_LENGTH {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            stream.read_exact(&mut byte)?;
+            buf.push(byte[0]);
+        }
+        let line = std::str::from_utf8(&buf[..buf.len() - 2])
+            .map_err(|_| invalid("PROXY v1 header is not ascii"))?;
+        return parse_v1(line);
+    }
+
+    stream.read_exact(&mut buf[5..])?;
+    if &buf[..12] != V2_SIGNATURE {
+        return Err(invalid("Missing PROXY protocol header"));
+    }
+    let len = usize::from(u16::from_be_bytes([buf[14], buf[15]]));
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    parse_v2(buf[12], buf[13], &payload)
+}
+
+fn parse_v1(line: &str) -> io::Result<Option<IpAddr>> {
+    let mut parts = line.split(' ');
+    parts.next();
+    match parts.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some(protocol @ ("TCP4" | "TCP6")) => parts
+            .next()
+            .and_then(|x| x.parse::<IpAddr>().ok())
+            .filter(|x| x.is_ipv4() == (protocol == "TCP4"))
+            .map(Some)
+            .ok_or_else(|| invalid("Invalid source address in PROXY v1 header")),
+        _ => Err(invalid("Invalid protocol in PROXY v1 header")),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> io::Result<Option<IpAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: health checks of the proxy itself
+        0 => return Ok(None),
+        1 => (),
+        _ => return Err(invalid("Invalid PROXY v2 command")),
+    }
+    match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let bytes: [u8; 4] = payload[..4].try_into().unwrap();
+            Ok(Some(Ipv4Addr::from(bytes).into()))
+        }
+        2 if payload.len() >= 36 => {
+            let bytes: [u8; 16] = payload[..16].try_into().unwrap();
+            Ok(Some(Ipv6Addr::from(bytes).into()))
+        }
+        0 | 3 => Ok(None),
+        _ => Err(invalid("Invalid address in PROXY v2 header")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(mut data: &[u8]) -> io::Result<Option<IpAddr>> {
+        read_header(&mut data)
+    }
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([0x20 | command, family]);
+        data.extend((payload.len() as u16).to_be_bytes());
+        data.extend(payload);
+        data
+    }
+
+    #[test]
+    fn v1() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /").unwrap(),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap(),
+            Some("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert_eq!(
+            read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn v1_errors() {
+        for data in [
+            &b"PROXY TCP4 2001:db8::1 2001:db8::2 1 2\r\n"[..],
+            b"PROXY TCP6 192.0.2.1 198.51.100.1 1 2\r\n",
+            b"PROXY TCP4 192.0.2\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1",
+            b"GET / HTTP/1.1\r\n\r\n",
+        ] {
+            assert!(read(data).is_err(), "{}", String::from_utf8_lossy(data));
+        }
+        // The longest valid line is 107 bytes
+        let line = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LENGTH - 16));
+        assert_eq!(line.len(), V1_MAX_LENGTH);
+        assert_eq!(read(line.as_bytes()).unwrap(), None);
+        let line = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LENGTH));
+        let error = read(line.as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "PROXY v1 header too long");
+    }
+
+    #[test]
+    fn v2_addresses() {
+        let mut tcp4 = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        tcp4.extend([0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(
+            read(&v2(1, 0x11, &tcp4)).unwrap(),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut tcp6 = source.octets().to_vec();
+        tcp6.extend([0; 20]);
+        assert_eq!(
+            read(&v2(1, 0x21, &tcp6)).unwrap(),
+            Some("2001:db8::1".parse().unwrap())
+        );
+        // Trailing TLVs are part of the payload
+        tcp4.extend([0x04, 0x00, 0x01, 0x00]);
+        assert_eq!(
+            read(&v2(1, 0x11, &tcp4)).unwrap(),
+            Some("192.0.2.1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn v2_without_address() {
+        // LOCAL, e.g. health checks of the proxy
+        assert_eq!(read(&v2(0, 0x11, &[0; 12])).unwrap(), None);
+        assert_eq!(read(&v2(0, 0x00, &[])).unwrap(), None);
+        // UNSPEC and AF_UNIX
+        assert_eq!(read(&v2(1, 0x00, &[])).unwrap(), None);
+        assert_eq!(read(&v2(1, 0x31, &[0; 216])).unwrap(), None);
+    }
+
+    #[test]
+    fn v2_errors() {
+        // Address shorter than its family requires
+        assert!(read(&v2(1, 0x11, &[192, 0, 2, 1])).is_err());
+        assert!(read(&v2(1, 0x21, &[0; 12])).is_err());
+        // Payload shorter than announced
+        let mut truncated = v2(1, 0x11, &[0; 12]);
+        truncated.truncate(20);
+        assert_eq!(
+            read(&truncated).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        // Truncated signature
+        assert!(read(&V2_SIGNATURE[..8]).is_err());
+        let mut data = v2(2, 0x11, &[0; 12]);
+        assert!(read(&data).is_err());
+        data[12] = 0x11;
+        assert!(read(&data).is_err());
+        assert!(read(&v2(1, 0x41, &[0; 12])).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use std::str::FromStr;
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const DAYS_IN_MONTH: [u32; 12] = [31, 29, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+
+/// A cron-like schedule with the five fields `minute hour day-of-month month day-of-week`.
+///
+/// Every field accepts `*`, single values, ranges (`1-5`), steps (`*/15`, `0-30/10`) and comma
+/// separated lists of these. Months and weekdays can also be given by their English three letter
+/// names, Sunday is both `0` and `7`. As in cron, a day matches if it matches either the
+/// day-of-month or the day-of-week field when both are restricted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// The times of day at which the schedule fires on `date`, in ascending order.
+    pub fn times_on(&self, date: NaiveDate) -> Vec<NaiveTime> {
+        if !self.matches_date(date) {
+            return Vec::new();
+        }
+        bits(self.hours)
+            .flat_map(|hour| bits(self.minutes).map(move |minute| (hour, minute)))
+            .map(|(hour, minute)| NaiveTime::from_hms_opt(hour, minute, 0).unwrap())
+            .collect()
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => day,
+            (true, false) => weekday,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("\"{s}\": expected 5 fields, got {}", fields.len()));
+        };
+        let error = |e: String| format!("\"{s}\": {e}");
+        let schedule = Self {
+            minutes: parse_field(minutes, 0, 59, &[]).map_err(error)?,
+            hours: parse_field(hours, 0, 23, &[]).map_err(error)?,
+            days: parse_field(days, 1, 31, &[]).map_err(error)?,
+            months: parse_field(months, 1, 12, &MONTHS).map_err(error)?,
+            weekdays: parse_weekdays(weekdays).map_err(error)?,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        };
+        let possible = (1..=12)
+            .filter(|x| schedule.months & (1 << x) != 0)
+            .any(|x| schedule.days & ((1 << (DAYS_IN_MONTH[x - 1] + 1)) - 1) != 0);
+        if !possible && schedule.any_weekday {
+            return Err(format!("\"{s}\": the day of the month never occurs"));
+        }
+        Ok(schedule)
+    }
+}
+
+/// A weekly recurring period of time, written as weekdays and a time range like
+/// `mon-fri 08:00-18:00`. A range ending before it starts extends into the next day, a range
+/// ending when it starts covers the whole day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessWindow {
+    weekdays: u64,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl AccessWindow {
+    pub fn contains(&self, time: NaiveDateTime) -> bool {
+        let weekday =
+            |date: NaiveDate| self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        let today = weekday(time.date());
+        if self.start == self.end {
+            return today;
+        }
+        let t = time.time();
+        if self.start < self.end {
+            today && self.start <= t && t < self.end
+        } else {
+            let yesterday = time.date().pred_opt().is_some_and(weekday);
+            (today && self.start <= t) || (yesterday && t < self.end)
+        }
+    }
+}
+
+impl FromStr for AccessWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |e: String| format!("\"{s}\": {e}");
+        let (weekdays, range) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| error("expected weekdays and a time range".into()))?;
+        let (start, end) = range
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| error(format!("invalid time range \"{range}\"")))?;
+        let time = |x: &str| {
+            NaiveTime::parse_from_str(x.trim(), "%H:%M")
+                .map_err(|_| error(format!("invalid time \"{x}\"")))
+        };
+        Ok(Self {
+            weekdays: parse_weekdays(weekdays).map_err(error)?,
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+}
+
+/// The indices of the set bits of `x`, in ascending order.
+fn bits(x: u64) -> impl Iterator<Item = u32> {
+    (0..64).filter(move |i| x & (1 << i) != 0)
+}
+
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let mut result = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|x| *x > 0)
+                    .ok_or_else(|| format!("invalid step \"{step}\""))?,
+            ),
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                parse_value(start, min, max, names)?,
+                parse_value(end, min, max, names)?,
+            )
+        } else {
+            let start = parse_value(range, min, max, names)?;
+            // "5/10" means every 10 starting at 5
+            (start, if part.contains('/') { max } else { start })
+        };
+        if start > end {
+            return Err(format!("invalid range \"{range}\""));
+        }
+        for i in (start..=end).step_by(step as usize) {
+            result |= 1 << i;
+        }
+    }
+    Ok(result)
+}
+
+/// Parses a day-of-week field, mapping `7` to Sunday.
+fn parse_weekdays(field: &str) -> Result<u64, String> {
+    let weekdays = parse_field(field, 0, 7, &WEEKDAYS)?;
+    if weekdays & (1 << 7) != 0 {
+        Ok(weekdays | 1)
+    } else {
+        Ok(weekdays)
+    }
+}
+
+fn parse_value(value: &str, min: u32, max: u32, names: &[&str]) -> Result<u32, String> {
+    if let Some(i) = names.iter().position(|x| x.eq_ignore_ascii_case(value)) {
+        return Ok(i as u32 + min);
+    }
+    value
+        .parse::<u32>()
+        .ok()
+        .filter(|x| (min..=max).contains(x))
+        .ok_or_else(|| format!("\"{value}\" is not between {min} and {max}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn schedule_parse_errors() {
+        for x in [
+            "0 3 * *",
+            "60 * * * *",
+            "* 5-2 * * *",
+            "*/0 * * * *",
+            "0 0 * * someday",
+            "0 0 0 * *",
+            "0 0 * 13 *",
+        ] {
+            assert!(x.parse::<Schedule>().is_err(), "{x}");
+        }
+        assert!("*/15 8-18 * jan-mar,dec 7".parse::<Schedule>().is_ok());
+    }
+
+    #[test]
+    fn schedule_never_matching() {
+        for x in ["0 0 31 feb *", "0 0 30,31 2 *", "0 0 31 apr,jun,sep,nov *"] {
+            assert!(x.parse::<Schedule>().is_err(), "{x}");
+        }
+        // These still match on the weekday, or in the other months
+        assert!("0 0 31 feb mon".parse::<Schedule>().is_ok());
+        assert!("0 0 31 feb,mar *".parse::<Schedule>().is_ok());
+        assert!("0 0 29 feb *".parse::<Schedule>().is_ok());
+    }
+
+    #[test]
+    fn schedule_times() {
+        let schedule: Schedule = "*/20 8,17 * * *".parse().unwrap();
+        let times: Vec<_> = schedule
+            .times_on(date("2024-06-07"))
+            .iter()
+            .map(|x| x.format("%H:%M").to_string())
+            .collect();
+        assert_eq!(
+            times,
+            ["08:00", "08:20", "08:40", "17:00", "17:20", "17:40"]
+        );
+    }
+
+    #[test]
+    fn schedule_days() {
+        // Either the day of the month or the weekday, as in cron
+        let schedule: Schedule = "0 0 1 * sun".parse().unwrap();
+        assert!(!schedule.times_on(date("2024-06-01")).is_empty());
+        assert!(!schedule.times_on(date("2024-06-09")).is_empty());
+        assert!(schedule.times_on(date("2024-06-10")).is_empty());
+        // Sunday is both 0 and 7
+        let schedule: Schedule = "0 0 * jun 7".parse().unwrap();
+        assert!(!schedule.times_on(date("2024-06-09")).is_empty());
+        assert!(schedule.times_on(date("2024-07-07")).is_empty());
+    }
+
+    #[test]
+    fn access_window() {
+        let window: AccessWindow = "mon-fri 08:00-18:00".parse().unwrap();
+        // 2024-06-07 is a Friday
+        assert!(window.contains(time("2024-06-07 08:00")));
+        assert!(window.contains(time("2024-06-07 17:59")));
+        assert!(!window.contains(time("2024-06-07 18:00")));
+        assert!(!window.contains(time("2024-06-07 07:59")));
+        assert!(!window.contains(time("2024-06-08 12:00")));
+    }
+
+    #[test]
+    fn access_window_overnight() {
+        let window: AccessWindow = "fri 22:00-02:00".parse().unwrap();
+        assert!(window.contains(time("2024-06-07 23:00")));
+        assert!(window.contains(time("2024-06-08 01:59")));
+        assert!(!window.contains(time("2024-06-08 02:00")));
+        assert!(!window.contains(time("2024-06-06 23:00")));
+        assert!(!window.contains(time("2024-06-07 01:00")));
+
+        let window: AccessWindow = "sun 00:00-00:00".parse().unwrap();
+        assert!(window.contains(time("2024-06-09 23:59")));
+        assert!(!window.contains(time("2024-06-10 00:00")));
+    }
+}
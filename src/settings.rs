@@ -1,36 +1,201 @@
 use std::net::IpAddr;
 use std::str::FromStr;
 
-use config::{Config, ConfigError, File};
-use serde;
-use serde::Deserialize;
+use chrono_tz::Tz;
+use config::{Config, ConfigError, File, Source};
+use serde::{Deserialize, Deserializer};
+use std::sync::{Arc, RwLock};
+use std::{env, vec};
 use tiny_http::HeaderField;
 use validator::Validate;
-use std::{env, vec};
+
+use crate::expiry::ExpiryMode;
+use crate::forwarded::ClientIpHeader;
+use crate::list_file::ListFiles;
+use crate::net::{IpNet, PrefixSet};
+use crate::proxy_auth::ProxyAuth;
+use crate::schedule::{AccessWindow, Schedule};
+
+/// A setting that can be given as a single value or a list of values.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+fn deserialize_header_field<'de, D: Deserializer<'de>>(d: D) -> Result<HeaderField, D::Error> {
+    let s = String::deserialize(d)?;
+    HeaderField::from_str(&s)
+        .map_err(|_| serde::de::Error::custom(format!("\"{s}\" is not a valid header name")))
+}
+
+fn deserialize_mode<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+    let s = String::deserialize(d)?;
+    u32::from_str_radix(&s, 8)
+        .ok()
+        .filter(|x| *x <= 0o7777)
+        .ok_or_else(|| serde::de::Error::custom(format!("\"{s}\" is not an octal file mode")))
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            Self::One(x) => vec![x],
+            Self::Many(x) => x,
+        }
+    }
+}
 
 #[derive(Debug, Validate, Deserialize)]
 #[allow(unused)]
 pub struct Settings {
     pub listen_address: String,
+    pub proxy_protocol: bool,
     pub threads: usize,
     #[serde(rename(deserialize = "headers"))]
     read_headers: Vec<String>,
     #[serde(skip)]
     pub headers: Vec<HeaderField>,
-    pub allow_list: Vec<IpAddr>,
+    #[serde(rename(deserialize = "required_headers"))]
+    read_required_headers: Vec<String>,
+    #[serde(skip)]
+    pub required_headers: Vec<HeaderField>,
+    #[serde(rename(deserialize = "allow_list"))]
+    read_allow_list: Vec<IpNet>,
+    #[serde(skip)]
+    pub allow_list: PrefixSet,
+    #[serde(rename(deserialize = "deny_list"))]
+    read_deny_list: Vec<IpNet>,
+    #[serde(skip)]
+    pub deny_list: PrefixSet,
+    pub allow_list_file: Option<String>,
+    pub deny_list_file: Option<String>,
+    /// Contents of `allow_list_file` and `deny_list_file`, kept across reloads
+    #[serde(skip)]
+    pub list_files: Arc<ListFiles>,
+    #[serde(rename(deserialize = "trusted_proxies"))]
+    read_trusted_proxies: Option<Vec<IpNet>>,
+    #[serde(skip)]
+    pub trusted_proxies: PrefixSet,
+    pub forwarded_hops: Option<usize>,
+    #[serde(rename(deserialize = "client_ip_headers"))]
+    read_client_ip_headers: Vec<String>,
+    #[serde(skip)]
+    pub client_ip_headers: Vec<ClientIpHeader>,
+    pub expiry: ExpiryMode,
+    /// Larger values would overflow the date of the expiry
+    #[validate(range(max = 36500))]
     pub days: u32,
     #[validate(range(min = 0, max = 23))]
     pub hour: u8,
     #[validate(range(min = 0, max = 59))]
     pub minute: u8,
+    pub timezone: Tz,
+    #[serde(rename(deserialize = "schedule"))]
+    read_schedule: OneOrMany<String>,
+    #[serde(skip)]
+    pub schedule: Vec<Schedule>,
+    #[serde(rename(deserialize = "access_windows"))]
+    read_access_windows: Vec<String>,
+    #[serde(skip)]
+    pub access_windows: Vec<AccessWindow>,
+    #[validate(range(min = 1))]
+    pub ttl: u32,
+    #[validate(range(min = 1))]
+    pub idle_timeout: u32,
+    #[validate(range(min = 1))]
+    pub max_lifetime: Option<u32>,
     pub prune_interval: u32,
+    pub shutdown_timeout: u32,
+    #[validate(range(min = 0, max = 128))]
+    pub ipv6_prefix_length: u8,
+    pub state_file: Option<String>,
+    #[validate(length(max = 2))]
+    pub proxy_secrets: Vec<String>,
+    pub proxy_auth: ProxyAuth,
+    #[serde(deserialize_with = "deserialize_header_field")]
+    pub proxy_auth_header: HeaderField,
+    pub proxy_auth_max_age: u32,
+    pub admin_token: Option<String>,
+    pub admin_listen: Option<String>,
+    #[serde(deserialize_with = "deserialize_mode")]
+    pub admin_socket_mode: u32,
+    #[serde(deserialize_with = "deserialize_header_field")]
+    pub user_header: HeaderField,
+    #[serde(deserialize_with = "deserialize_header_field")]
+    pub groups_header: HeaderField,
+    pub htpasswd_file: Option<String>,
+    pub totp_file: Option<String>,
+    pub forward_auth_url: Option<String>,
+    #[validate(range(min = 1))]
+    pub forward_auth_timeout: u32,
+    #[validate(range(max = 10))]
+    pub totp_skew: u8,
+    pub journal_file: Option<String>,
 }
 
 impl Settings {
+    /// Reads and validates `config_file`, or the default config file if it is `None`.
+    pub fn read(config_file: Option<&str>) -> Result<Self, String> {
+        let settings = match config_file {
+            Some(x) => Self::from_file(x),
+            None => Self::new(),
+        }
+        .map_err(|e| format!("Failed to parse config: {e}"))?;
+        settings
+            .validate()
+            .map_err(|e| format!("Failed to validate config: {e}"))?;
+        Ok(settings)
+    }
+
+    /// Whether `addr` is in `allow_list` or `allow_list_file`.
+    pub fn is_allow_listed(&self, addr: &IpAddr) -> bool {
+        self.allow_list.contains(addr) || self.list_files.allow.contains(addr)
+    }
+
+    /// Whether `addr` is in `deny_list` or `deny_list_file`.
+    pub fn is_deny_listed(&self, addr: &IpAddr) -> bool {
+        self.deny_list.contains(addr) || self.list_files.deny.contains(addr)
+    }
+
     pub fn new() -> Result<Self, ConfigError> {
-        let config_file = env::var("CONFIG").unwrap_or("config.toml".into());
+        Self::from_file(&env::var("CONFIG").unwrap_or("config.toml".into()))
+    }
+
+    pub fn from_file(config_file: &str) -> Result<Self, ConfigError> {
+        Self::from_source(File::with_name(config_file))
+    }
+
+    /// Parses the settings from the contents of a TOML config file.
+    #[cfg(test)]
+    pub fn from_toml(toml: &str) -> Result<Self, ConfigError> {
+        Self::from_source(File::from_str(toml, config::FileFormat::Toml))
+    }
+
+    /// Like `from_toml`, with `headers = []` and, unless `trusted_proxies` is set,
+    /// `client_ip_headers = []` added, so a test only has to set what it is about.
+    #[cfg(test)]
+    pub fn for_test(toml: &str) -> Result<Self, ConfigError> {
+        let set = |key| {
+            toml.lines()
+                .any(|x| x.split('=').next().map(str::trim) == Some(key))
+        };
+        let mut config = String::new();
+        if !set("headers") {
+            config.push_str("headers = []\n");
+        }
+        if !set("client_ip_headers") && !set("trusted_proxies") {
+            config.push_str("client_ip_headers = []\n");
+        }
+        config.push_str(toml);
+        Self::from_toml(&config)
+    }
+
+    fn from_source(source: impl Source + Send + Sync + 'static) -> Result<Self, ConfigError> {
         let s = Config::builder()
             .set_default("listen_address", "127.0.0.1:8080")?
+            .set_default("proxy_protocol", false)?
             .set_default("threads", 1)?
             .set_default(
                 "read_headers",
@@ -41,24 +206,164 @@ impl Settings {
                     "Remote-User",
                 ],
             )?
+            .set_default("required_headers", Vec::<String>::new())?
             .set_default("allow_list", Vec::<String>::new())?
+            .set_default("deny_list", Vec::<String>::new())?
+            .set_default("client_ip_headers", vec!["X-Forwarded-For"])?
+            .set_default("expiry", "fixed")?
             .set_default("days", 0)?
             .set_default("hour", 3)?
             .set_default("minute", 0)?
+            .set_default("timezone", "UTC")?
+            .set_default("schedule", Vec::<String>::new())?
+            .set_default("access_windows", Vec::<String>::new())?
+            .set_default("ttl", 86400)?
+            .set_default("idle_timeout", 28800)?
             .set_default("prune_interval", 3600)?
-            .add_source(File::with_name(&config_file))
+            .set_default("shutdown_timeout", 10)?
+            .set_default("ipv6_prefix_length", 128)?
+            .set_default("user_header", "Remote-User")?
+            .set_default("groups_header", "Remote-Groups")?
+            .set_default("totp_skew", 1)?
+            .set_default("forward_auth_timeout", 5)?
+            .set_default("admin_socket_mode", "0660")?
+            .set_default("proxy_secrets", Vec::<String>::new())?
+            .set_default("proxy_auth", "secret")?
+            .set_default("proxy_auth_header", "X-Proxy-Auth")?
+            .set_default("proxy_auth_max_age", 300)?
+            .add_source(source)
             .build()?;
 
         match s.try_deserialize::<Self>() {
             Err(e) => Err(e),
             Ok(mut s) => {
-                s.headers = s
-                    .read_headers
-                    .drain(0..)
-                    .map(|x| HeaderField::from_str(&x).expect("Failed to parse header"))
+                s.headers = parse_header_fields(s.read_headers.drain(0..))?;
+                s.required_headers = parse_header_fields(s.read_required_headers.drain(0..))?;
+                s.allow_list = s.read_allow_list.drain(0..).collect();
+                s.deny_list = s.read_deny_list.drain(0..).collect();
+                if s.read_trusted_proxies.is_none()
+                    && (s.proxy_protocol || !s.read_client_ip_headers.is_empty())
+                {
+                    return Err(ConfigError::Message(
+                        "trusted_proxies is required, set it to the addresses of the proxies in \
+                         front of ip-manager, or set client_ip_headers to [] to ignore forwarding \
+                         headers"
+                            .into(),
+                    ));
+                }
+                s.trusted_proxies = s
+                    .read_trusted_proxies
+                    .take()
+                    .into_iter()
+                    .flatten()
                     .collect();
+                s.client_ip_headers = s
+                    .read_client_ip_headers
+                    .drain(0..)
+                    .map(|x| ClientIpHeader::from_str(&x))
+                    .collect::<Result<_, _>>()
+                    .map_err(ConfigError::Message)?;
+                s.schedule = std::mem::replace(&mut s.read_schedule, OneOrMany::Many(Vec::new()))
+                    .into_vec()
+                    .iter()
+                    .map(|x| Schedule::from_str(x))
+                    .collect::<Result<_, _>>()
+                    .map_err(ConfigError::Message)?;
+                s.access_windows = s
+                    .read_access_windows
+                    .drain(0..)
+                    .map(|x| AccessWindow::from_str(&x))
+                    .collect::<Result<_, _>>()
+                    .map_err(ConfigError::Message)?;
+                if s.admin_token.is_none()
+                    && s.admin_listen
+                        .as_deref()
+                        .is_some_and(|x| !x.starts_with("unix:"))
+                {
+                    return Err(ConfigError::Message(
+                        "admin_listen on a TCP address requires admin_token".into(),
+                    ));
+                }
+                if s.forward_auth_url.is_some() && s.htpasswd_file.is_some() {
+                    return Err(ConfigError::Message(
+                        "forward_auth_url and htpasswd_file can't be used together".into(),
+                    ));
+                }
+                if s.proxy_secrets.iter().any(|x| x.trim().is_empty()) {
+                    return Err(ConfigError::Message(
+                        "proxy_secrets can't contain an empty secret".into(),
+                    ));
+                }
+                if s.expiry == ExpiryMode::Schedule && s.schedule.is_empty() {
+                    return Err(ConfigError::Message(
+                        "expiry \"schedule\" requires a schedule".into(),
+                    ));
+                }
                 Ok(s)
             }
         }
     }
 }
+
+fn parse_header_fields(
+    fields: impl Iterator<Item = String>,
+) -> Result<Vec<HeaderField>, ConfigError> {
+    fields
+        .map(|x| {
+            HeaderField::from_str(&x)
+                .map_err(|_| ConfigError::Message(format!("invalid header \"{x}\"")))
+        })
+        .collect()
+}
+
+/// The settings in use, which are replaced as a whole when the config file is reloaded.
+pub struct SharedSettings {
+    config_file: Option<String>,
+    current: RwLock<Arc<Settings>>,
+}
+
+impl SharedSettings {
+    /// `config_file` is the file given on the command line, if any.
+    pub fn new(config_file: Option<String>, settings: Settings) -> Self {
+        Self {
+            config_file,
+            current: RwLock::new(Arc::new(settings)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<Settings> {
+        self.current.read().expect("Settings are poisoned").clone()
+    }
+
+    pub fn store(&self, settings: Settings) {
+        *self.current.write().expect("Settings are poisoned") = Arc::new(settings);
+    }
+
+    /// Reads and validates the config file again without replacing the current settings.
+    pub fn read(&self) -> Result<Settings, String> {
+        Settings::read(self.config_file.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config() {
+        let settings = Settings::from_toml(include_str!("config.toml")).unwrap();
+        assert!(settings
+            .trusted_proxies
+            .contains(&"127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn empty_proxy_secret() {
+        for secrets in [r#"[""]"#, r#"["secret", " "]"#] {
+            let error = Settings::for_test(&format!("proxy_secrets = {secrets}")).unwrap_err();
+            assert!(error.to_string().contains("empty secret"), "{error}");
+        }
+        let settings = Settings::for_test(r#"proxy_secrets = ["secret"]"#).unwrap();
+        assert_eq!(settings.proxy_secrets, ["secret"]);
+    }
+}
@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tiny_http::Header;
+
+use crate::WhitelistElement;
+
+/// Permissions of the snapshot and the journal, as they contain the saved headers of users.
+const FILE_MODE: u32 = 0o600;
+
+#[derive(Serialize, Deserialize)]
+struct StoredElement {
+    ip: IpAddr,
+    #[serde(default = "Utc::now")]
+    authorized_at: DateTime<Utc>,
+    valid_until: DateTime<Utc>,
+    headers: Vec<(String, String)>,
+}
+
+impl StoredElement {
+    fn new(ip: &IpAddr, element: &WhitelistElement) -> Self {
+        Self {
+            ip: *ip,
+            authorized_at: element.authorized_at,
+            valid_until: element.valid_until,
+            headers: element
+                .headers
+                .iter()
+                .map(|x| (x.field.to_string(), x.value.to_string()))
+                .collect(),
+        }
+    }
+
+    fn into_element(self) -> (IpAddr, WhitelistElement) {
+        let headers = self
+            .headers
+            .iter()
+            .filter_map(|(field, value)| {
+                let header = Header::from_bytes(field.as_bytes(), value.as_bytes());
+                if header.is_err() {
+                    warn!(
+                        "Dropping invalid saved header for {}: \"{field}: {value}\"",
+                        self.ip
+                    );
+                }
+                header.ok()
+            })
+            .collect();
+        (
+            self.ip,
+            WhitelistElement {
+                authorized_at: self.authorized_at,
+                valid_until: self.valid_until,
+                headers,
+            },
+        )
+    }
+}
+
+/// A change to the whitelist, as written to the journal.
+pub enum Event<'a> {
+    Allow(&'a IpAddr, &'a WhitelistElement),
+    Revoke(&'a IpAddr),
+    Expire(&'a IpAddr),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Record {
+    Allow(StoredElement),
+    Revoke { ip: IpAddr },
+    Expire { ip: IpAddr },
+}
+
+impl Record {
+    fn new(event: &Event) -> Self {
+        match event {
+            Event::Allow(ip, element) => Record::Allow(StoredElement::new(ip, element)),
+            Event::Revoke(ip) => Record::Revoke { ip: **ip },
+            Event::Expire(ip) => Record::Expire { ip: **ip },
+        }
+    }
+
+    fn apply(self, list: &mut HashMap<IpAddr, WhitelistElement>) {
+        match self {
+            Record::Allow(x) => {
+                let (ip, element) = x.into_element();
+                list.insert(ip, element);
+            }
+            Record::Revoke { ip } | Record::Expire { ip } => {
+                list.remove(&ip);
+            }
+        }
+    }
+}
+
+struct Journal {
+    file: Option<File>,
+    records: usize,
+}
+
+/// Persistent whitelist storage consisting of a snapshot and an append-only journal of the
+/// changes made since the snapshot was written.
+pub struct Store {
+    snapshot: PathBuf,
+    journal_path: PathBuf,
+    journal: Mutex<Journal>,
+}
+
+impl Store {
+    pub fn new(snapshot: PathBuf, journal_path: PathBuf) -> Self {
+        Self {
+            snapshot,
+            journal_path,
+            journal: Mutex::new(Journal {
+                file: None,
+                records: 0,
+            }),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.snapshot
+    }
+
+    /// Reads the snapshot and replays the journal on top of it. A torn record at the end of the
+    /// journal, as left behind by a crash during a write, is dropped and cut off the file.
+    pub fn load(&self) -> io::Result<HashMap<IpAddr, WhitelistElement>> {
+        let mut list = match fs::read(&self.snapshot) {
+            Ok(data) => {
+                let stored: Vec<StoredElement> = serde_json::from_slice(&data)?;
+                stored
+                    .into_iter()
+                    .map(StoredElement::into_element)
+                    .collect()
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut journal = self.journal.lock().expect("Journal is poisoned");
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .mode(FILE_MODE)
+            .open(&self.journal_path)?;
+        let mut reader = BufReader::new(&file);
+        let mut line = Vec::new();
+        let mut valid_len = 0;
+        let mut records = 0;
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 {
+                break;
+            }
+            match serde_json::from_slice::<Record>(&line) {
+                Ok(record) if line.ends_with(b"\n") => {
+                    record.apply(&mut list);
+                    valid_len += read as u64;
+                    records += 1;
+                }
+                _ => {
+                    if reader.fill_buf()?.is_empty() {
+                        warn!(
+                            "Dropping torn record at the end of {}",
+                            self.journal_path.display()
+                        );
+                        file.set_len(valid_len)?;
+                        break;
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Corrupt record in {} at byte {valid_len}",
+                            self.journal_path.display()
+                        ),
+                    ));
+                }
+            }
+        }
+        debug!("Replayed {records} journal records");
+        journal.file = Some(file);
+        journal.records = records;
+        Ok(list)
+    }
+
+    /// Appends a change to the journal.
+    pub fn record(&self, event: Event) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&Record::new(&event))?;
+        line.push(b'\n');
+        let mut journal = self.journal.lock().expect("Journal is poisoned");
+        if journal.file.is_none() {
+            journal.file = Some(
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .mode(FILE_MODE)
+                    .open(&self.journal_path)?,
+            );
+        }
+        let file = journal.file.as_mut().unwrap();
+        file.write_all(&line)?;
+        file.sync_data()?;
+        journal.records += 1;
+        Ok(())
+    }
+
+    /// Writes `list` as the new snapshot and empties the journal. The caller has to make sure no
+    /// changes are made to the whitelist until this returns.
+    pub fn compact(&self, list: &HashMap<IpAddr, WhitelistElement>) -> io::Result<()> {
+        let mut journal = self.journal.lock().expect("Journal is poisoned");
+        if journal.records == 0 && self.snapshot.exists() {
+            return Ok(());
+        }
+        let stored: Vec<_> = list
+            .iter()
+            .map(|(ip, x)| StoredElement::new(ip, x))
+            .collect();
+        let tmp = tmp_path(&self.snapshot);
+        {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(FILE_MODE)
+                .open(&tmp)?;
+            // A leftover file keeps its permissions
+            file.set_permissions(fs::Permissions::from_mode(FILE_MODE))?;
+            serde_json::to_writer(&mut file, &stored)?;
+            file.flush()?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.snapshot)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(FILE_MODE)
+            .open(&self.journal_path)?;
+        // Journals written by earlier versions were readable by everyone
+        file.set_permissions(fs::Permissions::from_mode(FILE_MODE))?;
+        file.sync_all()?;
+        journal.file = None;
+        debug!("Compacted {} journal records", journal.records);
+        journal.records = 0;
+        Ok(())
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    /// A snapshot and journal path unique to this test run.
+    fn paths(name: &str) -> (PathBuf, PathBuf) {
+        let base = env::temp_dir().join(format!("ip-manager-{}-{name}", process::id()));
+        let _ = fs::remove_file(&base);
+        let _ = fs::remove_file(base.with_extension("journal"));
+        (base.clone(), base.with_extension("journal"))
+    }
+
+    fn element(valid_until: &str, headers: &[(&str, &str)]) -> WhitelistElement {
+        WhitelistElement {
+            authorized_at: DateTime::parse_from_rfc3339("2024-06-01T12:00:00.123456789Z")
+                .unwrap()
+                .into(),
+            valid_until: DateTime::parse_from_rfc3339(valid_until).unwrap().into(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| Header::from_bytes(*k, *v).unwrap())
+                .collect(),
+        }
+    }
+
+    /// The entries in a comparable form.
+    fn dump(list: &HashMap<IpAddr, WhitelistElement>) -> Vec<String> {
+        let mut entries: Vec<_> = list
+            .iter()
+            .map(|(ip, x)| {
+                let headers: Vec<_> = x.headers.iter().map(|x| x.to_string()).collect();
+                format!(
+                    "{ip} {} {} {}",
+                    x.authorized_at.to_rfc3339(),
+                    x.valid_until.to_rfc3339(),
+                    headers.join("; ")
+                )
+            })
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    fn cleanup(snapshot: &Path, journal: &Path) {
+        let _ = fs::remove_file(snapshot);
+        let _ = fs::remove_file(journal);
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let (snapshot, journal) = paths("snapshot_round_trip");
+        let list = HashMap::from([
+            (
+                "192.0.2.1".parse().unwrap(),
+                element(
+                    "2030-01-01T03:00:00Z",
+                    &[("Remote-User", "alice"), ("Remote-Groups", "admins,users")],
+                ),
+            ),
+            (
+                "2001:db8::".parse().unwrap(),
+                element("2030-01-02T03:00:00Z", &[]),
+            ),
+        ]);
+        let store = Store::new(snapshot.clone(), journal.clone());
+        store.load().unwrap();
+        store.compact(&list).unwrap();
+
+        let restored = Store::new(snapshot.clone(), journal.clone())
+            .load()
+            .unwrap();
+        assert_eq!(dump(&restored), dump(&list));
+        for path in [&snapshot, &journal] {
+            let mode = fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, FILE_MODE, "{}", path.display());
+        }
+        cleanup(&snapshot, &journal);
+    }
+
+    #[test]
+    fn private_files() {
+        let (snapshot, journal) = paths("private_files");
+        fs::write(&journal, "").unwrap();
+        fs::set_permissions(&journal, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::write(tmp_path(&snapshot), "").unwrap();
+        fs::set_permissions(tmp_path(&snapshot), fs::Permissions::from_mode(0o644)).unwrap();
+        let store = Store::new(snapshot.clone(), journal.clone());
+        store.load().unwrap();
+        store.compact(&HashMap::new()).unwrap();
+        for path in [&snapshot, &journal] {
+            let mode = fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, FILE_MODE, "{}", path.display());
+        }
+        cleanup(&snapshot, &journal);
+    }
+
+    #[test]
+    fn snapshot_without_authorized_at() {
+        let (snapshot, journal) = paths("snapshot_without_authorized_at");
+        // Written before authorized_at was saved
+        fs::write(
+            &snapshot,
+            r#"[{"ip":"192.0.2.1","valid_until":"2030-01-01T03:00:00Z","headers":[["Remote-User","alice"]]}]"#,
+        )
+        .unwrap();
+        let before = Utc::now();
+        let restored = Store::new(snapshot.clone(), journal.clone())
+            .load()
+            .unwrap();
+        let x = &restored[&"192.0.2.1".parse().unwrap()];
+        assert!(before <= x.authorized_at && x.authorized_at <= Utc::now());
+        assert_eq!(x.valid_until.to_rfc3339(), "2030-01-01T03:00:00+00:00");
+        assert_eq!(x.headers[0].to_string(), "Remote-User: alice");
+        cleanup(&snapshot, &journal);
+    }
+
+    #[test]
+    fn torn_journal_record() {
+        let (snapshot, journal) = paths("torn_journal_record");
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let c: IpAddr = "192.0.2.3".parse().unwrap();
+        let store = Store::new(snapshot.clone(), journal.clone());
+        store.load().unwrap();
+        store
+            .compact(&HashMap::from([
+                (a, element("2030-01-01T03:00:00Z", &[])),
+                (b, element("2030-01-01T03:00:00Z", &[])),
+            ]))
+            .unwrap();
+        let allowed = element("2030-01-02T03:00:00Z", &[("Remote-User", "carol")]);
+        store.record(Event::Allow(&c, &allowed)).unwrap();
+        store.record(Event::Revoke(&a)).unwrap();
+        let valid_len = fs::metadata(&journal).unwrap().len();
+        // A crash in the middle of writing the next record
+        let mut file = OpenOptions::new().append(true).open(&journal).unwrap();
+        file.write_all(br#"{"event":"expire","ip":"192.0"#).unwrap();
+        drop(file);
+
+        let store = Store::new(snapshot.clone(), journal.clone());
+        let list = store.load().unwrap();
+        let mut ips: Vec<_> = list.keys().copied().collect();
+        ips.sort();
+        assert_eq!(ips, [b, c]);
+        assert_eq!(
+            dump(&HashMap::from([(c, list[&c].clone())])),
+            dump(&HashMap::from([(c, allowed)]))
+        );
+        assert_eq!(fs::metadata(&journal).unwrap().len(), valid_len);
+
+        // New records start on a fresh line and survive compaction
+        store.record(Event::Expire(&b)).unwrap();
+        let list = Store::new(snapshot.clone(), journal.clone())
+            .load()
+            .unwrap();
+        assert_eq!(list.keys().collect::<Vec<_>>(), [&c]);
+        store.compact(&list).unwrap();
+        assert_eq!(fs::metadata(&journal).unwrap().len(), 0);
+        let compacted = Store::new(snapshot.clone(), journal.clone())
+            .load()
+            .unwrap();
+        assert_eq!(dump(&compacted), dump(&list));
+        cleanup(&snapshot, &journal);
+    }
+
+    #[test]
+    fn corrupt_journal_record() {
+        let (snapshot, journal) = paths("corrupt_journal_record");
+        let contents = "{\"event\":\"revoke\",\"ip\":\"192.0.2.1\"}\ngarbage\n{\"event\":\"revoke\",\"ip\":\"192.0.2.2\"}\n";
+        fs::write(&journal, contents).unwrap();
+        let error = Store::new(snapshot.clone(), journal.clone())
+            .load()
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // Only a torn record at the end is cut off
+        assert_eq!(fs::read_to_string(&journal).unwrap(), contents);
+        cleanup(&snapshot, &journal);
+    }
+
+    #[test]
+    fn missing_snapshot() {
+        let (snapshot, journal) = paths("missing_snapshot");
+        let store = Store::new(snapshot.clone(), journal.clone());
+        assert!(store.load().unwrap().is_empty());
+        cleanup(&snapshot, &journal);
+    }
+}
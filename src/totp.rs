@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::admin::constant_time_eq;
+
+/// Length of a time step in seconds.
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+
+/// The last time step used by each user, so a code can't be used twice.
+static LAST_STEPS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// The base32 encoded secret of `name` in the TOTP file at `path`, which contains lines of
+/// `name:secret`.
+pub fn secret(path: &str, name: &str) -> Result<Option<Vec<u8>>, String> {
+    let file = fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+    let Some((_, secret)) = file
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .filter_map(|x| x.split_once(':'))
+        .find(|(x, _)| *x == name)
+    else {
+        return Ok(None);
+    };
+    let secret: String = secret
+        .chars()
+        .filter(|x| !x.is_whitespace() && *x != '=')
+        .map(|x| x.to_ascii_uppercase())
+        .collect();
+    BASE32_NOPAD
+        .decode(secret.as_bytes())
+        .map(Some)
+        .map_err(|e| format!("Invalid TOTP secret of {name}: {e}"))
+}
+
+/// Checks `code` for the time `now` in seconds since the epoch, accepting codes up to `skew`
+/// time steps before or after it. Every time step is only accepted once per user.
+pub fn verify(name: &str, secret: &[u8], code: &str, now: u64, skew: u8) -> bool {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|x| x.is_ascii_digit()) {
+        return false;
+    }
+    let current = now / STEP;
+    let Some(step) = (current.saturating_sub(skew.into())..=current + u64::from(skew)).find(|x| {
+        let expected = format!("{:0width$}", hotp(secret, *x), width = DIGITS as usize);
+        constant_time_eq(expected.as_bytes(), code.as_bytes())
+    }) else {
+        return false;
+    };
+    let mut last_steps = LAST_STEPS.lock().expect("TOTP state is poisoned");
+    if last_steps.get(name).is_some_and(|x| step <= *x) {
+        return false;
+    }
+    last_steps.insert(name.to_string(), step);
+    true
+}
+
+/// A page asking for the code, which is posted back to the same URL as `code`.
+pub fn form(message: Option<&str>) -> String {
+    let message = message
+        .map(|x| format!("<p class=\"error\">{x}</p>"))
+        .unwrap_or_default();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>ip-manager</title>
+<style>body {{ font-family: sans-serif; margin: 3em auto; max-width: 20em; }} .error {{ color: #b00; }}</style>
+</head>
+<body>
+<form method="post">
+<h1>Authentication code</h1>
+{message}
+<p><input name="code" inputmode="numeric" pattern="[0-9]*" autocomplete="one-time-code" autofocus required></p>
+<p><button type="submit">Verify</button></p>
+</form>
+</body>
+</html>
+"#
+    )
+}
+
+/// The HOTP value (RFC 4226) of `counter` with HMAC-SHA1.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).unwrap();
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc6238_vectors() {
+        // The last six digits of the SHA1 test vectors of RFC 6238
+        for (time, code) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ] {
+            assert_eq!(hotp(SECRET, time / STEP), code);
+        }
+    }
+
+    #[test]
+    fn skew_and_replay() {
+        let now = 1234567890;
+        assert!(!verify("a", SECRET, "005924", now + 2 * STEP, 1));
+        assert!(verify("a", SECRET, "005924", now + STEP, 1));
+        assert!(!verify("a", SECRET, "005924", now + STEP, 1));
+        // Codes of earlier time steps are rejected after a later one was used
+        let earlier = format!("{:06}", hotp(SECRET, now / STEP - 1));
+        assert!(!verify("a", SECRET, &earlier, now, 1));
+        assert!(verify("b", SECRET, "005924", now, 0));
+    }
+}